@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use auth::*;
+use mqtt::QoS;
+use topic;
+
+/* ACL file, one rule per line:
+ *
+ *     topic read $SYS/#           rules before any `user` line apply to everyone
+ *     pattern write devices/%c/#  patterns apply to everyone, %u is the username and %c the client id
+ *     user alice
+ *     topic readwrite home/#      rules after `user` apply to that user only
+ */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn allows(&self, action: Action) -> bool {
+        matches!(
+            (*self, action),
+            (Access::ReadWrite, _) | (Access::Read, Action::Subscribe) | (Access::Write, Action::Publish)
+        )
+    }
+
+    fn parse(s: &str) -> Option<Access> {
+        match s {
+            "read" => Some(Access::Read),
+            "write" => Some(Access::Write),
+            "readwrite" => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Rule {
+    pub access: Access,
+    pub topic: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct AclRules {
+    pub defaults: Vec<Rule>,
+    pub users: HashMap<String, Vec<Rule>>,
+    pub patterns: Vec<Rule>,
+}
+
+/// Replaces %u and %c, or returns None if the rule cannot apply to this client
+fn substitute(pattern: &str, identity: &ClientIdentity) -> Option<String> {
+    // Wildcards or separators in an id would widen the rule to other clients' topics
+    let safe = |s: &str| !s.contains(['+', '#', '/', '%']);
+    let mut result = pattern.to_string();
+    if result.contains("%c") {
+        if !safe(&identity.client_id) {
+            return None;
+        }
+        result = result.replace("%c", &identity.client_id);
+    }
+    if result.contains("%u") {
+        match identity.username {
+            Some(ref username) if safe(username) => result = result.replace("%u", username),
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+/// Splits off the first word, which ends at a space or tab
+fn first_word(line: &str) -> (&str, &str) {
+    match line.find([' ', '\t']) {
+        Some(i) => (&line[..i], line[i..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+impl AclRules {
+    /// Topics are the rest of the line after the access level, so they may contain spaces
+    pub fn parse(contents: &str) -> Result<AclRules, String> {
+        let mut rules = AclRules::default();
+        let mut user: Option<String> = None;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, rest) = first_word(line);
+            if keyword == "user" {
+                if rest.is_empty() {
+                    return Err(format!("line {}: expected a username", i + 1));
+                }
+                user = Some(rest.to_string());
+                continue;
+            }
+            if keyword != "topic" && keyword != "pattern" {
+                return Err(format!("line {}: unknown keyword `{}`", i + 1, keyword));
+            }
+            let (access, topic) = first_word(rest);
+            let rule = match Access::parse(access) {
+                Some(access) if !topic.is_empty() => Rule {
+                    access: access,
+                    topic: topic.to_string(),
+                },
+                // The access level may be left out, meaning readwrite
+                None if !rest.is_empty() => Rule {
+                    access: Access::ReadWrite,
+                    topic: rest.to_string(),
+                },
+                _ => {
+                    return Err(format!(
+                        "line {}: expected `{} [read|write|readwrite] <topic>`",
+                        i + 1,
+                        keyword
+                    ))
+                }
+            };
+            match (keyword, &user) {
+                ("pattern", _) => rules.patterns.push(rule),
+                (_, Some(user)) => rules.users.entry(user.clone()).or_insert_with(Vec::new).push(rule),
+                (_, None) => rules.defaults.push(rule),
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn allows(&self, identity: &ClientIdentity, topic: &str, action: Action) -> bool {
+        let no_rules = Vec::new();
+        let user_rules = match identity.username {
+            Some(ref username) => self.users.get(username).unwrap_or(&no_rules),
+            None => &no_rules,
+        };
+        let fixed = self.defaults.iter().chain(user_rules.iter());
+        if fixed.filter(|r| r.access.allows(action)).any(|r| topic::covers(&r.topic, topic)) {
+            return true;
+        }
+        self.patterns
+            .iter()
+            .filter(|r| r.access.allows(action))
+            .filter_map(|r| substitute(&r.topic, identity))
+            .any(|allowed| topic::covers(&allowed, topic))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AclFile {
+    path: PathBuf,
+    rules: AclRules,
+}
+
+impl AclFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<AclFile, String> {
+        let mut file = AclFile {
+            path: path.as_ref().to_path_buf(),
+            rules: AclRules::default(),
+        };
+        file.reload()?;
+        Ok(file)
+    }
+
+    /// Re-reads the file, keeping the current rules if it cannot be parsed
+    pub fn reload(&mut self) -> Result<(), String> {
+        let mut contents = String::new();
+        File::open(&self.path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        self.rules = AclRules::parse(&contents).map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        Ok(())
+    }
+}
+
+impl Authorizer for AclFile {
+    fn authorize(&self, identity: &ClientIdentity, topic: &str, _: &QoS, action: Action) -> bool {
+        self.rules.allows(identity, topic, action)
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        AclFile::reload(self)
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use acl::*;
+
+    fn identity(client_id: &str, username: Option<&str>) -> ClientIdentity {
+        ClientIdentity {
+            client_id: client_id.to_string(),
+            username: username.map(|u| u.to_string()),
+            peer: "127.0.0.1:5000".parse().unwrap(),
+            topics: None,
+        }
+    }
+
+    const RULES: &str = "
+        # everyone
+        topic read public/#
+        pattern write devices/%c/#
+        pattern readwrite users/%u/+
+
+        user alice
+        topic readwrite home/#
+        topic write alerts
+    ";
+
+    #[test]
+    fn applies_default_and_user_rules() {
+        let rules = AclRules::parse(RULES).unwrap();
+        let alice = identity("phone", Some("alice"));
+        let bob = identity("laptop", Some("bob"));
+        assert!(rules.allows(&alice, "home/kitchen/temp", Action::Publish));
+        assert!(rules.allows(&alice, "home/+/temp", Action::Subscribe));
+        assert!(!rules.allows(&bob, "home/kitchen/temp", Action::Publish));
+        assert!(rules.allows(&alice, "alerts", Action::Publish));
+        assert!(!rules.allows(&alice, "alerts", Action::Subscribe));
+        assert!(rules.allows(&bob, "public/news", Action::Subscribe));
+        assert!(!rules.allows(&bob, "public/news", Action::Publish));
+    }
+
+    #[test]
+    fn substitutes_patterns() {
+        let rules = AclRules::parse(RULES).unwrap();
+        let alice = identity("phone", Some("alice"));
+        assert!(rules.allows(&alice, "devices/phone/battery", Action::Publish));
+        assert!(!rules.allows(&alice, "devices/laptop/battery", Action::Publish));
+        assert!(rules.allows(&alice, "users/alice/inbox", Action::Subscribe));
+        assert!(!rules.allows(&alice, "users/alice/#", Action::Subscribe));
+        assert!(!rules.allows(&identity("x", None), "users/%u/inbox", Action::Subscribe));
+        assert!(!rules.allows(&identity("#", None), "devices/a/b", Action::Publish));
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let err = AclRules::parse("topic read a\nallow a\n").unwrap_err();
+        assert_eq!(err, "line 2: unknown keyword `allow`");
+        let err = AclRules::parse("user\n").unwrap_err();
+        assert_eq!(err, "line 1: expected a username");
+        let err = AclRules::parse("topic read\n").unwrap_err();
+        assert_eq!(err, "line 1: expected `topic [read|write|readwrite] <topic>`");
+    }
+
+    #[test]
+    fn reads_topics_with_spaces() {
+        let rules = AclRules::parse("topic\tread  living room/+ \t\ntopic office lamp\n").unwrap();
+        let bob = identity("laptop", Some("bob"));
+        assert!(rules.allows(&bob, "living room/lamp", Action::Subscribe));
+        assert!(!rules.allows(&bob, "living room/lamp", Action::Publish));
+        assert!(rules.allows(&bob, "office lamp", Action::Publish));
+        assert!(!rules.allows(&bob, "office", Action::Publish));
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+/* MQTT 5 topic aliases, which stand in for topic names on one connection */
+
+/// Topics counted for outbound aliasing before old counts are aged out
+const MAX_TRACKED_TOPICS: usize = 1024;
+
+/// Aliases a client set up for the topics it publishes to
+pub struct InboundAliases {
+    maximum: u16,
+    topics: HashMap<u16, String>,
+}
+
+impl InboundAliases {
+    pub fn new(maximum: u16) -> InboundAliases {
+        InboundAliases {
+            maximum: maximum,
+            topics: HashMap::new(),
+        }
+    }
+
+    /// Returns the topic name of a PUBLISH, remembering the alias if it sets one
+    pub fn resolve(&mut self, topic: String, alias: Option<u16>) -> Result<String, &'static str> {
+        let alias = match alias {
+            Some(alias) => alias,
+            None => return Ok(topic),
+        };
+        if alias == 0 || alias > self.maximum {
+            return Err("Topic alias exceeds the Topic Alias Maximum");
+        }
+        if topic.is_empty() {
+            return match self.topics.get(&alias) {
+                Some(topic) => Ok(topic.clone()),
+                None => Err("Topic alias was never set"),
+            };
+        }
+        self.topics.insert(alias, topic.clone());
+        Ok(topic)
+    }
+}
+
+/// Aliases for the topics sent most often to one client
+pub struct OutboundAliases {
+    maximum: u16,
+    counts: HashMap<String, u64>,
+    aliases: HashMap<String, u16>,
+}
+
+impl OutboundAliases {
+    pub fn new(maximum: u16) -> OutboundAliases {
+        OutboundAliases {
+            maximum: maximum,
+            counts: HashMap::new(),
+            aliases: HashMap::new(),
+        }
+    }
+
+    /// Counts a PUBLISH to `topic` and returns the alias to send it with, if any,
+    /// and whether the client already knows it (so the topic name can be left out)
+    pub fn assign(&mut self, topic: &str) -> Option<(u16, bool)> {
+        if self.maximum == 0 {
+            return None;
+        }
+        if self.counts.len() >= MAX_TRACKED_TOPICS && !self.counts.contains_key(topic) {
+            self.age();
+        }
+        let count = {
+            let count = self.counts.entry(topic.to_string()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        if let Some(&alias) = self.aliases.get(topic) {
+            return Some((alias, true));
+        }
+        // One-off topics are not worth an alias
+        if count < 2 {
+            return None;
+        }
+        let alias = if self.aliases.len() < self.maximum as usize {
+            let aliases = &self.aliases;
+            (1..=self.maximum).find(|alias| !aliases.values().any(|a| a == alias)).unwrap()
+        } else {
+            // Take the alias of the least used topic, if this one is used more
+            let counts = &self.counts;
+            let (victim, victim_count) = self
+                .aliases
+                .keys()
+                .map(|t| (t.clone(), counts.get(t).cloned().unwrap_or(0)))
+                .min_by_key(|&(_, count)| count)
+                .unwrap();
+            if victim_count >= count {
+                return None;
+            }
+            self.aliases.remove(&victim).unwrap()
+        };
+        self.aliases.insert(topic.to_string(), alias);
+        Some((alias, false))
+    }
+
+    /// Drops the alias of `topic`, when the PUBLISH setting it up was never sent
+    pub fn forget(&mut self, topic: &str) {
+        self.aliases.remove(topic);
+    }
+
+    /// Halves every count so recent traffic decides, forgetting rarely sent topics
+    fn age(&mut self) {
+        let aliases = &self.aliases;
+        for count in self.counts.values_mut() {
+            *count /= 2;
+        }
+        self.counts.retain(|topic, count| *count > 0 || aliases.contains_key(topic));
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use alias::*;
+
+    #[test]
+    fn resolves_inbound_aliases() {
+        let mut aliases = InboundAliases::new(2);
+        assert_eq!(aliases.resolve("a/b".to_string(), None), Ok("a/b".to_string()));
+        assert_eq!(aliases.resolve("a/b".to_string(), Some(1)), Ok("a/b".to_string()));
+        assert_eq!(aliases.resolve(String::new(), Some(1)), Ok("a/b".to_string()));
+        assert_eq!(aliases.resolve("c".to_string(), Some(1)), Ok("c".to_string()));
+        assert_eq!(aliases.resolve(String::new(), Some(1)), Ok("c".to_string()));
+        assert_eq!(aliases.resolve(String::new(), Some(2)), Err("Topic alias was never set"));
+        assert_eq!(
+            aliases.resolve("d".to_string(), Some(3)),
+            Err("Topic alias exceeds the Topic Alias Maximum")
+        );
+        assert!(aliases.resolve("d".to_string(), Some(0)).is_err());
+    }
+
+    #[test]
+    fn aliases_frequent_outbound_topics() {
+        let mut aliases = OutboundAliases::new(1);
+        assert_eq!(aliases.assign("a"), None);
+        assert_eq!(aliases.assign("a"), Some((1, false)));
+        assert_eq!(aliases.assign("a"), Some((1, true)));
+        // "b" has to be sent more often than "a" to take its alias
+        for _ in 0..3 {
+            assert_eq!(aliases.assign("b"), None);
+        }
+        assert_eq!(aliases.assign("b"), Some((1, false)));
+        assert_eq!(aliases.assign("a"), None);
+        aliases.forget("b");
+        assert_eq!(aliases.assign("a"), Some((1, false)));
+        assert_eq!(OutboundAliases::new(0).assign("a"), None);
+    }
+}
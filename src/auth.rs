@@ -0,0 +1,208 @@
+use std::net::SocketAddr;
+
+use mqtt::*;
+use passwd::{PasswordFile, ScramCredentials};
+use topic;
+
+/* Pluggable authentication (on CONNECT) and authorization (on PUBLISH/SUBSCRIBE) */
+
+/// Who a client is, as established by the authenticator on CONNECT
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClientIdentity {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub peer: SocketAddr,
+    /// Topics the credentials themselves are limited to, e.g. by token claims
+    pub topics: Option<TopicGrants>,
+}
+
+impl ClientIdentity {
+    pub fn new(payload: &ConnectPayload, peer: &SocketAddr) -> ClientIdentity {
+        ClientIdentity {
+            client_id: payload.client_id.clone(),
+            username: payload.username.clone(),
+            peer: *peer,
+            topics: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TopicGrants {
+    pub publish: Vec<String>,
+    pub subscribe: Vec<String>,
+}
+
+impl TopicGrants {
+    pub fn allows(&self, topic_name: &str, action: Action) -> bool {
+        let filters = match action {
+            Action::Publish => &self.publish,
+            Action::Subscribe => &self.subscribe,
+        };
+        filters.iter().any(|f| topic::covers(f, topic_name))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    Publish,
+    Subscribe,
+}
+
+/// Check too slow for the reactor thread, like hashing a password, run on a thread pool
+pub type PasswordCheck = Box<dyn FnOnce() -> bool + Send>;
+
+/// Decision about a CONNECT
+pub enum Verdict {
+    Accept(ClientIdentity),
+    /// Rejects the client with this CONNACK code
+    Reject(ConnAckReturnCode),
+    /// Accepts the client only if the check passes
+    Check(ClientIdentity, PasswordCheck),
+}
+
+impl Verdict {
+    /// Decides right away, running any check on the current thread
+    pub fn wait(self) -> Result<ClientIdentity, ConnAckReturnCode> {
+        match self {
+            Verdict::Accept(identity) => Ok(identity),
+            Verdict::Reject(code) => Err(code),
+            Verdict::Check(identity, check) => match check() {
+                true => Ok(identity),
+                false => Err(ConnAckReturnCode::BadAuth),
+            },
+        }
+    }
+}
+
+pub trait Authenticator {
+    /// Accepts the client, rejects it or leaves the decision to a slow check
+    fn authenticate(&self, header: &ConnectHeader, payload: &ConnectPayload, peer: &SocketAddr) -> Verdict;
+
+    /// Stored keys of `username` for SCRAM-SHA-256 enhanced authentication,
+    /// None if the user is unknown or the authenticator has no such credentials
+    fn scram_credentials(&self, _username: &str) -> Option<ScramCredentials> {
+        None
+    }
+
+    /// Whether SCRAM-SHA-256 can be offered at all
+    fn supports_scram(&self) -> bool {
+        false
+    }
+
+    /// Re-reads whatever the decisions are based on, called on SIGHUP
+    fn reload(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub trait Authorizer {
+    /// `topic` is a topic name for `Publish` and a topic filter for `Subscribe`
+    fn authorize(&self, identity: &ClientIdentity, topic: &str, qos: &QoS, action: Action) -> bool;
+
+    /// Re-reads whatever the decisions are based on, called on SIGHUP
+    fn reload(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Lets everyone in and allows everything, the default without an auth config
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _: &ConnectHeader, payload: &ConnectPayload, peer: &SocketAddr) -> Verdict {
+        Verdict::Accept(ClientIdentity::new(payload, peer))
+    }
+}
+
+impl Authorizer for AllowAll {
+    fn authorize(&self, _: &ClientIdentity, _: &str, _: &QoS, _: Action) -> bool {
+        true
+    }
+}
+
+impl Authenticator for PasswordFile {
+    fn authenticate(&self, _: &ConnectHeader, payload: &ConnectPayload, peer: &SocketAddr) -> Verdict {
+        let credentials = payload.username.as_ref().and_then(|username| self.get(username));
+        match (credentials, &payload.password) {
+            // Hashing takes long on purpose, so it happens off the reactor thread
+            (Some(credentials), Some(password)) => {
+                let credentials = credentials.clone();
+                let password = password.clone();
+                let check = move || credentials.verify(&password);
+                Verdict::Check(ClientIdentity::new(payload, peer), Box::new(check))
+            }
+            _ => Verdict::Reject(ConnAckReturnCode::BadAuth),
+        }
+    }
+
+    fn scram_credentials(&self, username: &str) -> Option<ScramCredentials> {
+        self.get(username).and_then(|credentials| credentials.scram()).cloned()
+    }
+
+    fn supports_scram(&self) -> bool {
+        true
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        PasswordFile::reload(self)
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use auth::*;
+    use mqtt::reader::*;
+    use passwd::{Credentials, PasswordHash};
+    use bytes::Bytes;
+
+    fn connect(username: &str, password: &str) -> (ConnectHeader, ConnectPayload) {
+        let mut data = vec![0x10, 0x00, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0xC2, 0x00, 0x3C];
+        for s in &["client", username, password] {
+            data.push(0);
+            data.push(s.len() as u8);
+            data.extend_from_slice(s.as_bytes());
+        }
+        data[1] = (data.len() - 2) as u8;
+        let packet = read_packet(Bytes::from(data)).unwrap();
+        let header = match packet.var_header {
+            VariableHeader::Connect(ref h) => h.clone(),
+            _ => panic!(),
+        };
+        (header, packet.get_connect_payload().unwrap())
+    }
+
+    #[test]
+    fn password_file_checks_credentials() {
+        let mut passwords = PasswordFile::new("/nonexistent");
+        passwords.set("alice", Credentials::Pbkdf2(PasswordHash::new(b"secret", 10)));
+        let peer = "127.0.0.1:5000".parse().unwrap();
+
+        let (header, payload) = connect("alice", "secret");
+        let identity = passwords.authenticate(&header, &payload, &peer).wait().unwrap();
+        assert_eq!(identity.client_id, "client");
+        assert_eq!(identity.username, Some("alice".to_string()));
+
+        let (header, payload) = connect("alice", "wrong");
+        let err = passwords.authenticate(&header, &payload, &peer).wait().unwrap_err();
+        assert_eq!(err, ConnAckReturnCode::BadAuth);
+        let (header, payload) = connect("bob", "secret");
+        let err = passwords.authenticate(&header, &payload, &peer).wait().unwrap_err();
+        assert_eq!(err, ConnAckReturnCode::BadAuth);
+    }
+
+    #[test]
+    fn password_file_offers_only_scram_entries_for_scram() {
+        let mut passwords = PasswordFile::new("/nonexistent");
+        let credentials = ScramCredentials::new(b"secret", 10);
+        passwords.set("alice", Credentials::Pbkdf2(PasswordHash::new(b"secret", 10)));
+        passwords.set("bob", Credentials::Scram(credentials.clone()));
+        assert_eq!(passwords.scram_credentials("alice"), None);
+        assert_eq!(passwords.scram_credentials("bob"), Some(credentials));
+
+        let peer = "127.0.0.1:5000".parse().unwrap();
+        let (header, payload) = connect("bob", "secret");
+        assert!(passwords.authenticate(&header, &payload, &peer).wait().is_ok());
+    }
+}
@@ -0,0 +1,107 @@
+extern crate clap;
+extern crate picomq;
+extern crate rpassword;
+
+use std::process;
+use std::str::FromStr;
+
+use clap::{App, Arg};
+use picomq::passwd::{Credentials, PasswordFile, PasswordHash, ScramCredentials, DEFAULT_ITERATIONS};
+
+fn fail(message: String) -> ! {
+    eprintln!("Error: {}", message);
+    process::exit(1);
+}
+
+fn read_password() -> String {
+    let password = rpassword::prompt_password_stderr("Password: ")
+        .unwrap_or_else(|e| fail(e.to_string()));
+    let confirmation = rpassword::prompt_password_stderr("Reenter password: ")
+        .unwrap_or_else(|e| fail(e.to_string()));
+    if password != confirmation {
+        fail("Passwords do not match".to_string());
+    }
+    password
+}
+
+fn main() {
+    let matches = App::new("picomq-passwd")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Manages picomq password files")
+        .arg(
+            Arg::with_name("create")
+                .short("c")
+                .long("create")
+                .help("Creates a new password file, overwriting an existing one"),
+        )
+        .arg(
+            Arg::with_name("delete")
+                .short("D")
+                .long("delete")
+                .help("Removes the user from the password file")
+                .conflicts_with_all(&["create", "password"]),
+        )
+        .arg(
+            Arg::with_name("password")
+                .short("b")
+                .long("batch")
+                .value_name("PASSWORD")
+                .help("Takes the password from the command line instead of prompting")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scram")
+                .short("s")
+                .long("scram")
+                .help("Stores SCRAM-SHA-256 keys, which also check plain passwords")
+                .conflicts_with("delete"),
+        )
+        .arg(
+            Arg::with_name("iterations")
+                .short("i")
+                .long("iterations")
+                .value_name("COUNT")
+                .help("PBKDF2 iteration count")
+                .takes_value(true)
+                .validator(|v| match u32::from_str(&v) {
+                    Ok(i) if i > 0 => Ok(()),
+                    _ => Err("must be a positive number".to_string()),
+                }),
+        )
+        .arg(Arg::with_name("FILE").required(true).index(1))
+        .arg(Arg::with_name("USERNAME").required(true).index(2))
+        .get_matches();
+
+    let path = matches.value_of("FILE").unwrap();
+    let username = matches.value_of("USERNAME").unwrap();
+    if username.contains(':') {
+        fail("Username must not contain ':'".to_string());
+    }
+    let mut file = if matches.is_present("create") {
+        PasswordFile::new(path)
+    } else {
+        PasswordFile::load(path).unwrap_or_else(|e| fail(e))
+    };
+
+    if matches.is_present("delete") {
+        if !file.remove(username) {
+            fail(format!("User {} not found", username));
+        }
+    } else {
+        let iterations = matches
+            .value_of("iterations")
+            .map(|i| u32::from_str(i).unwrap())
+            .unwrap_or(DEFAULT_ITERATIONS);
+        let password = match matches.value_of("password") {
+            Some(p) => p.to_string(),
+            None => read_password(),
+        };
+        let credentials = if matches.is_present("scram") {
+            Credentials::Scram(ScramCredentials::new(password.as_bytes(), iterations))
+        } else {
+            Credentials::Pbkdf2(PasswordHash::new(password.as_bytes(), iterations))
+        };
+        file.set(username, credentials);
+    }
+    file.save().unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+}
@@ -0,0 +1,1819 @@
+extern crate bytes;
+extern crate futures;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::sync::mpsc::UnboundedSender;
+
+use acl::AclFile;
+use alias::OutboundAliases;
+use auth::*;
+use config::{
+    AuthConfig, Config, DeniedPublish, FeaturesConfig, LimitsConfig, RedirectConfig, SharedStrategy, MAX_PACKET_SIZE,
+};
+use mqtt::*;
+use jwt::JwtAuthenticator;
+use mqtt::writer;
+use passwd::{PasswordFile, ScramCredentials};
+use topic;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub retain: bool,
+    /// MQTT 5 properties passed on to the subscribers
+    pub properties: Properties,
+    /// Client id of the publisher, None for messages from the broker itself
+    pub sender: Option<String>,
+    /// When the message stops being delivered, from the Message Expiry Interval
+    pub expiry: Option<Instant>,
+}
+
+impl Message {
+    /// A message without properties, as published by the broker itself
+    pub fn new(topic: &str, payload: Bytes, qos: QoS, retain: bool) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: payload,
+            qos: qos,
+            retain: retain,
+            properties: Properties::default(),
+            sender: None,
+            expiry: None,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expiry.is_some_and(|expiry| expiry <= Instant::now())
+    }
+
+    /// Properties to send, with the expiry interval reduced by the time spent in the broker
+    fn outgoing_properties(&self) -> Properties {
+        let mut properties = self.properties.clone();
+        if let Some(expiry) = self.expiry {
+            let left = expiry.duration_since(Instant::now());
+            let seconds = left.as_secs() + if left.subsec_nanos() > 0 { 1 } else { 0 };
+            properties.message_expiry_interval = Some(seconds as u32);
+        }
+        properties
+    }
+}
+
+/// Will of a connection, published if it ends without a clean DISCONNECT
+#[derive(Debug, PartialEq, Clone)]
+pub struct Will {
+    pub message: Message,
+    /// Seconds the client has to come back before the will goes out, MQTT 5 only
+    pub delay_interval: u32,
+}
+
+/// Session Expiry Interval of sessions which are never discarded
+pub const NEVER_EXPIRES: u32 = 0xFFFF_FFFF;
+
+fn min_qos(a: &QoS, b: &QoS) -> QoS {
+    if a.to_byte() <= b.to_byte() {
+        a.clone()
+    } else {
+        b.clone()
+    }
+}
+
+/// The copy of a routed message a subscription with `options` gets
+fn delivered(message: &Message, options: &SubscriptionOptions) -> Message {
+    Message {
+        qos: min_qos(&message.qos, &options.qos),
+        // Retain is only kept for messages sent because of a new subscription,
+        // unless the subscriber asked for it
+        retain: message.retain && options.retain_as_published,
+        properties: Properties {
+            subscription_identifiers: options.subscription_identifier.into_iter().collect(),
+            ..message.properties.clone()
+        },
+        ..message.clone()
+    }
+}
+
+/// What an MQTT 5 client announced in CONNECT about the packets it accepts
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClientLimits {
+    /// QoS 1 and 2 messages the client handles at once
+    pub receive_maximum: u16,
+    pub maximum_packet_size: u32,
+    pub topic_alias_maximum: u16,
+}
+
+impl Default for ClientLimits {
+    fn default() -> ClientLimits {
+        ClientLimits {
+            receive_maximum: 65535,
+            maximum_packet_size: MAX_PACKET_SIZE,
+            topic_alias_maximum: 0,
+        }
+    }
+}
+
+/* Subscriptions and undelivered messages of one client id, outliving connections for a while */
+struct Session {
+    peer: Option<SocketAddr>,
+    version: ProtocolVersion,
+    /// Seconds the session is kept after the connection is gone
+    expiry_interval: u32,
+    disconnected_at: Option<Instant>,
+    subscriptions: HashMap<String, SubscriptionOptions>,
+    last_packet_id: u16,
+    /// QoS 1 and 2 messages that arrived while the client was offline
+    /// or had as many in flight as it accepts, with the shared subscription
+    /// they came through if any
+    queue: VecDeque<(Message, Option<String>)>,
+    /// QoS 1 and 2 messages sent but not acknowledged yet, resent when the session
+    /// is resumed. `None` once the client received a QoS 2 one and only PUBCOMP is missing.
+    inflight: HashMap<u16, Option<Message>>,
+    /// QoS 1 messages from shared subscriptions sent but not acknowledged yet,
+    /// with the subscription they came through
+    shared_inflight: HashMap<u16, (String, Message)>,
+    /// Limits of the current connection
+    limits: ClientLimits,
+    /// Topic aliases of the current connection, MQTT 5 only
+    aliases: OutboundAliases,
+    /// Keep Alive of the current connection in seconds, 0 if it has none
+    keep_alive: u16,
+    last_packet: Instant,
+    will: Option<Will>,
+    /// Will of the last connection waiting for its delay to pass
+    delayed_will: Option<(Message, Instant)>,
+    /// Topic the current connection got as response information, it may use the ones below
+    response_topic: Option<String>,
+}
+
+impl Session {
+    fn new(peer: SocketAddr, version: ProtocolVersion, expiry_interval: u32) -> Session {
+        Session {
+            peer: Some(peer),
+            version: version,
+            expiry_interval: expiry_interval,
+            disconnected_at: None,
+            subscriptions: HashMap::new(),
+            last_packet_id: 0,
+            queue: VecDeque::new(),
+            inflight: HashMap::new(),
+            shared_inflight: HashMap::new(),
+            limits: ClientLimits::default(),
+            aliases: OutboundAliases::new(0),
+            keep_alive: 0,
+            last_packet: Instant::now(),
+            will: None,
+            delayed_will: None,
+            response_topic: None,
+        }
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        self.last_packet_id = match self.last_packet_id {
+            65535 => 1,
+            id => id + 1,
+        };
+        self.last_packet_id
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.disconnected_at {
+            Some(_) if self.expiry_interval == NEVER_EXPIRES => false,
+            Some(at) => at + Duration::from_secs(self.expiry_interval as u64) <= now,
+            None => false,
+        }
+    }
+
+    /// Whether one and a half times the Keep Alive passed without a packet
+    fn is_timed_out(&self, now: Instant) -> bool {
+        let grace = Duration::from_millis(self.keep_alive as u64 * 1500);
+        self.keep_alive > 0 && self.last_packet + grace <= now
+    }
+
+    fn is_online(&self, connections: &HashMap<SocketAddr, UnboundedSender<Bytes>>) -> bool {
+        self.peer.is_some_and(|peer| connections.contains_key(&peer))
+    }
+
+    /// Options of all non-shared subscriptions of `client_id` matching the message
+    /// combined, if any: the highest QoS, the RETAIN flag kept if any keeps it
+    /// and every Subscription Identifier
+    fn combined_options(&self, client_id: &str, message: &Message) -> Option<(SubscriptionOptions, Vec<u32>)> {
+        let own = message.sender.as_ref().is_some_and(|sender| sender == client_id);
+        self.subscriptions
+            .iter()
+            .filter(|&(filter, _)| topic::split_shared(filter).is_none())
+            .filter(|&(_, options)| !(own && options.no_local))
+            .filter(|&(filter, _)| topic::matches(filter, &message.topic))
+            .map(|(_, options)| options.clone())
+            .fold(None, |combined: Option<(SubscriptionOptions, Vec<u32>)>, options| match combined {
+                Some((combined, mut identifiers)) => {
+                    identifiers.extend(options.subscription_identifier);
+                    let options = SubscriptionOptions {
+                        qos: if options.qos.to_byte() > combined.qos.to_byte() { options.qos } else { combined.qos },
+                        retain_as_published: combined.retain_as_published || options.retain_as_published,
+                        ..combined
+                    };
+                    Some((options, identifiers))
+                }
+                None => {
+                    let identifiers = options.subscription_identifier.into_iter().collect();
+                    Some((options, identifiers))
+                }
+            })
+    }
+
+    /// Returns the packet id if a QoS 1 or 2 message went out right away
+    fn send(
+        &mut self,
+        connections: &HashMap<SocketAddr, UnboundedSender<Bytes>>,
+        message: &Message,
+    ) -> Option<u16> {
+        self.send_shared(connections, message, None)
+    }
+
+    /// Sends a message which came through the shared subscription `shared`, if any,
+    /// keeping QoS 1 ones until acknowledged so another member can take them over
+    fn send_shared(
+        &mut self,
+        connections: &HashMap<SocketAddr, UnboundedSender<Bytes>>,
+        message: &Message,
+        shared: Option<&str>,
+    ) -> Option<u16> {
+        if message.is_expired() {
+            return None;
+        }
+        if !self.is_online(connections) {
+            if message.qos != QoS::AtMostOnce && self.expiry_interval > 0 {
+                self.queue.push_back((message.clone(), shared.map(|s| s.to_string())));
+            }
+            return None;
+        }
+        // Messages already waiting for the client's Receive Maximum go first
+        let waiting = !self.queue.is_empty() || self.inflight.len() >= self.limits.receive_maximum as usize;
+        if message.qos != QoS::AtMostOnce && waiting {
+            self.queue.push_back((message.clone(), shared.map(|s| s.to_string())));
+            return None;
+        }
+        self.transmit_shared(connections, message, shared)
+    }
+
+    fn transmit_shared(
+        &mut self,
+        connections: &HashMap<SocketAddr, UnboundedSender<Bytes>>,
+        message: &Message,
+        shared: Option<&str>,
+    ) -> Option<u16> {
+        let packet_id = self.transmit(connections, message);
+        if let (Some(packet_id), Some(filter)) = (packet_id, shared) {
+            if message.qos == QoS::AtLeastOnce {
+                self.shared_inflight.insert(packet_id, (filter.to_string(), message.clone()));
+            }
+        }
+        packet_id
+    }
+
+    /// Sends queued messages as far as the client's Receive Maximum allows
+    fn flush(&mut self, connections: &HashMap<SocketAddr, UnboundedSender<Bytes>>) {
+        while self.is_online(connections) && self.inflight.len() < self.limits.receive_maximum as usize {
+            match self.queue.pop_front() {
+                Some((ref message, ref shared)) if !message.is_expired() => {
+                    self.transmit_shared(connections, message, shared.as_ref().map(|s| &s[..]));
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+    }
+
+    fn transmit(
+        &mut self,
+        connections: &HashMap<SocketAddr, UnboundedSender<Bytes>>,
+        message: &Message,
+    ) -> Option<u16> {
+        let packet_id = match message.qos {
+            QoS::AtMostOnce => 0,
+            _ => self.next_packet_id(),
+        };
+        if !self.write(connections, message, packet_id, false) || packet_id == 0 {
+            return None;
+        }
+        self.inflight.insert(packet_id, Some(message.clone()));
+        Some(packet_id)
+    }
+
+    /// Sends what the last connection left unacknowledged, before anything new
+    fn retransmit(&mut self, connections: &HashMap<SocketAddr, UnboundedSender<Bytes>>) {
+        let mut inflight: Vec<(u16, Option<Message>)> = self.inflight.drain().collect();
+        inflight.sort_by_key(|&(id, _)| id);
+        for (packet_id, message) in inflight {
+            match message {
+                Some(message) => {
+                    if self.write(connections, &message, packet_id, true) {
+                        self.inflight.insert(packet_id, Some(message));
+                    }
+                }
+                None => {
+                    let packet = match self.version {
+                        ProtocolVersion::V5 => {
+                            writer::v5::ack(&PacketType::PubRel, packet_id, ReasonCode::Success, &Properties::default())
+                        }
+                        _ => writer::pubrel(packet_id),
+                    };
+                    if let Some(tx) = self.peer.and_then(|peer| connections.get(&peer)) {
+                        let _ = tx.unbounded_send(packet);
+                    }
+                    self.inflight.insert(packet_id, None);
+                }
+            }
+        }
+    }
+
+    /// Returns whether the PUBLISH went out, it does not when it is too large for the client
+    fn write(
+        &mut self,
+        connections: &HashMap<SocketAddr, UnboundedSender<Bytes>>,
+        message: &Message,
+        packet_id: u16,
+        dup: bool,
+    ) -> bool {
+        let tx = match self.peer.and_then(|peer| connections.get(&peer)) {
+            Some(tx) => tx,
+            None => return false,
+        };
+        let mut new_alias = false;
+        let packet = match self.version {
+            ProtocolVersion::V5 => {
+                let mut properties = message.outgoing_properties();
+                // Once the client knows an alias the topic name can be left out
+                let topic = match self.aliases.assign(&message.topic) {
+                    Some((alias, known)) => {
+                        properties.topic_alias = Some(alias);
+                        new_alias = !known;
+                        if known { "" } else { &message.topic[..] }
+                    }
+                    None => &message.topic[..],
+                };
+                writer::v5::publish(
+                    topic,
+                    packet_id,
+                    &message.qos,
+                    message.retain,
+                    dup,
+                    &properties,
+                    &message.payload,
+                )
+            }
+            _ => writer::publish(
+                &message.topic,
+                packet_id,
+                &message.qos,
+                message.retain,
+                dup,
+                &message.payload,
+            ),
+        };
+        // Too large for the client, which is as good as delivered
+        if packet.len() > self.limits.maximum_packet_size as usize {
+            debug!(
+                "Skipped a {} byte message to {:?} over the client's Maximum Packet Size",
+                packet.len(),
+                message.topic
+            );
+            if new_alias {
+                self.aliases.forget(&message.topic);
+            }
+            return false;
+        }
+        let _ = tx.unbounded_send(packet);
+        true
+    }
+}
+
+/* Round-robin position and sticky assignments of one shared subscription */
+#[derive(Default)]
+struct SharedGroup {
+    next: usize,
+    /// Member client id per publisher client id, for the sticky strategy
+    sticky: HashMap<String, String>,
+}
+
+/* Broker configuration for embedders, starting from the defaults */
+pub struct BrokerBuilder {
+    config: Config,
+    authenticator: Option<Box<dyn Authenticator>>,
+    authorizer: Option<Box<dyn Authorizer>>,
+}
+
+impl BrokerBuilder {
+    pub fn new() -> BrokerBuilder {
+        BrokerBuilder {
+            config: Config::default(),
+            authenticator: None,
+            authorizer: None,
+        }
+    }
+
+    /// Replaces the whole configuration, listeners are not used by the broker itself
+    pub fn config(mut self, config: Config) -> BrokerBuilder {
+        self.config = config;
+        self
+    }
+
+    pub fn limits(mut self, limits: LimitsConfig) -> BrokerBuilder {
+        self.config.limits = limits;
+        self
+    }
+
+    pub fn features(mut self, features: FeaturesConfig) -> BrokerBuilder {
+        self.config.features = features;
+        self
+    }
+
+    /// Used instead of the authenticator configured in `auth`
+    pub fn authenticator(mut self, authenticator: Box<dyn Authenticator>) -> BrokerBuilder {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Used instead of the ACL file configured in `auth`
+    pub fn authorizer(mut self, authorizer: Box<dyn Authorizer>) -> BrokerBuilder {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Checks the configuration and loads the auth files it names
+    pub fn build(self) -> Result<Broker, String> {
+        self.config.validate().map_err(|e| e.to_string())?;
+        let mut broker = Broker::new(&self.config)?;
+        if let Some(authenticator) = self.authenticator {
+            broker.set_authenticator(authenticator);
+        }
+        if let Some(authorizer) = self.authorizer {
+            broker.set_authorizer(authorizer);
+        }
+        broker.check_enhanced_auth()?;
+        Ok(broker)
+    }
+}
+
+impl Default for BrokerBuilder {
+    fn default() -> BrokerBuilder {
+        BrokerBuilder::new()
+    }
+}
+
+/* Broker-wide state shared by all connections */
+pub struct Broker {
+    authenticator: Box<dyn Authenticator>,
+    authorizer: Box<dyn Authorizer>,
+    denied_publish: DeniedPublish,
+    require_enhanced_auth: bool,
+    /// Outbound queue of every open connection
+    connections: HashMap<SocketAddr, UnboundedSender<Bytes>>,
+    /// Client id of the session each connection is attached to
+    clients: HashMap<SocketAddr, String>,
+    /// Addresses of local clients, held until the client is dropped even once it is taken over
+    local_peers: HashSet<SocketAddr>,
+    sessions: HashMap<String, Session>,
+    retained: HashMap<String, Message>,
+    /// Keyed by the whole `$share/<group>/<filter>` filter
+    shared_groups: HashMap<String, SharedGroup>,
+    retain_enabled: bool,
+    wildcards_enabled: bool,
+    shared_enabled: bool,
+    subscription_identifiers_enabled: bool,
+    shared_strategy: SharedStrategy,
+    max_topic_length: usize,
+    max_topic_levels: usize,
+    max_packet_size: u32,
+    max_connections: usize,
+    log_payloads: bool,
+    receive_maximum: u16,
+    topic_alias_maximum: u16,
+    response_topic_prefix: String,
+    max_queued_messages: usize,
+    redirect: RedirectConfig,
+    /// Whether clients are sent to `redirect.server_reference`, switched by the operator
+    redirecting: bool,
+}
+
+impl Broker {
+    pub fn new(config: &Config) -> Result<Broker, String> {
+        let authenticator: Box<dyn Authenticator> = match config.auth {
+            AuthConfig { jwt: Some(ref jwt), .. } => Box::new(JwtAuthenticator::load(jwt)?),
+            AuthConfig { password_file: Some(ref path), .. } => Box::new(PasswordFile::load(path)?),
+            _ => Box::new(AllowAll),
+        };
+        let authorizer: Box<dyn Authorizer> = match config.auth.acl_file {
+            Some(ref path) => Box::new(AclFile::load(path)?),
+            None => Box::new(AllowAll),
+        };
+        let broker = Broker {
+            authenticator: authenticator,
+            authorizer: authorizer,
+            denied_publish: config.auth.denied_publish,
+            require_enhanced_auth: config.auth.require_enhanced_auth,
+            connections: HashMap::new(),
+            clients: HashMap::new(),
+            local_peers: HashSet::new(),
+            sessions: HashMap::new(),
+            retained: HashMap::new(),
+            shared_groups: HashMap::new(),
+            retain_enabled: config.features.retain,
+            wildcards_enabled: config.features.wildcard_subscriptions,
+            shared_enabled: config.features.shared_subscriptions,
+            subscription_identifiers_enabled: config.features.subscription_identifiers,
+            shared_strategy: config.features.shared_subscription_strategy,
+            max_topic_length: config.limits.max_topic_length,
+            max_topic_levels: config.limits.max_topic_levels,
+            max_packet_size: config.limits.max_packet_size,
+            max_connections: config.limits.max_connections,
+            log_payloads: config.log.payloads,
+            receive_maximum: config.limits.receive_maximum,
+            topic_alias_maximum: config.limits.topic_alias_maximum,
+            response_topic_prefix: config.features.response_topic_prefix.clone(),
+            max_queued_messages: config.limits.max_queued_messages,
+            redirect: config.redirect.clone(),
+            redirecting: false,
+        };
+        broker.check_enhanced_auth()?;
+        Ok(broker)
+    }
+
+    pub fn builder() -> BrokerBuilder {
+        BrokerBuilder::new()
+    }
+
+    /// Requiring enhanced authentication locks everyone out unless the authenticator offers it
+    fn check_enhanced_auth(&self) -> Result<(), String> {
+        if self.require_enhanced_auth && !self.authenticator.supports_scram() {
+            return Err("auth.require_enhanced_auth needs an authenticator offering SCRAM".to_string());
+        }
+        Ok(())
+    }
+
+    /* Embedders can replace the built-in auth sources */
+    pub fn set_authenticator(&mut self, authenticator: Box<dyn Authenticator>) {
+        self.authenticator = authenticator;
+    }
+
+    pub fn set_authorizer(&mut self, authorizer: Box<dyn Authorizer>) {
+        self.authorizer = authorizer;
+    }
+
+    /// Re-reads auth data, keeping the previous rules if it is broken
+    pub fn reload(&mut self) {
+        match self.authenticator.reload() {
+            Ok(_) => info!("Reloaded authentication data"),
+            Err(e) => error!("Failed to reload authentication data: {}", e),
+        }
+        match self.authorizer.reload() {
+            Ok(_) => info!("Reloaded authorization rules"),
+            Err(e) => error!("Failed to reload authorization rules: {}", e),
+        }
+    }
+
+    pub fn authenticate(
+        &self,
+        header: &ConnectHeader,
+        payload: &ConnectPayload,
+        peer: &SocketAddr,
+    ) -> Verdict {
+        self.authenticator.authenticate(header, payload, peer)
+    }
+
+    /// Salted password of `username` for SCRAM-SHA-256, if the authenticator has one
+    pub fn scram_credentials(&self, username: &str) -> Option<ScramCredentials> {
+        self.authenticator.scram_credentials(username)
+    }
+
+    pub fn supports_scram(&self) -> bool {
+        self.authenticator.supports_scram()
+    }
+
+    /// Whether clients have to use enhanced authentication instead of passwords in CONNECT
+    pub fn requires_enhanced_auth(&self) -> bool {
+        self.require_enhanced_auth
+    }
+
+    /// Checks the identity's own topic grants, then the authorizer. Connections granted
+    /// a response topic may use the topics below it whatever the authorizer says.
+    pub fn authorize(&self, identity: &ClientIdentity, topic: &str, qos: &QoS, action: Action) -> bool {
+        if let Some(ref grants) = identity.topics {
+            if !grants.allows(topic, action) {
+                return false;
+            }
+        }
+        let response_topic = self
+            .sessions
+            .get(&identity.client_id)
+            .filter(|session| session.peer == Some(identity.peer))
+            .and_then(|session| session.response_topic.as_ref());
+        if let Some(response_topic) = response_topic {
+            if topic == response_topic || topic.starts_with(&format!("{}/", response_topic)) {
+                return true;
+            }
+        }
+        self.authorizer.authorize(identity, topic, qos, action)
+    }
+
+    /// Checks a topic name or filter against the configured limits
+    pub fn check_topic(&self, topic_name: &str) -> Result<(), &'static str> {
+        topic::check_limits(topic_name, self.max_topic_length, self.max_topic_levels)
+    }
+
+    pub fn denied_publish(&self) -> DeniedPublish {
+        self.denied_publish
+    }
+
+    /// Where requests to the client should be answered, for an attached MQTT 5 client
+    /// asking for response information. Its connection may use the topics below from now on.
+    pub fn grant_response_topic(&mut self, identity: &ClientIdentity) -> Option<String> {
+        // Clients pick their own ids, so the topic is bound to the authenticated user too
+        let username = identity.username.as_ref()?;
+        // Wildcards or separators would reach into other clients' topics
+        let unsafe_name = |name: &str| name.is_empty() || name.contains(['+', '#', '/']);
+        if self.response_topic_prefix.is_empty() || unsafe_name(username) || unsafe_name(&identity.client_id) {
+            return None;
+        }
+        let topic = format!("{}/{}/{}", self.response_topic_prefix, username, identity.client_id);
+        let session = self.sessions.get_mut(&identity.client_id)?;
+        session.response_topic = Some(topic.clone());
+        Some(topic)
+    }
+
+    pub fn subscription_identifiers_enabled(&self) -> bool {
+        self.subscription_identifiers_enabled
+    }
+
+    pub fn max_packet_size(&self) -> u32 {
+        self.max_packet_size
+    }
+
+    /// Open connections at which new ones are refused, 0 for no limit
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Whether received packets are logged with their payloads
+    pub fn log_payloads(&self) -> bool {
+        self.log_payloads
+    }
+
+    /// QoS 2 messages an MQTT 5 client may have waiting for PUBREL at once
+    pub fn receive_maximum(&self) -> u16 {
+        self.receive_maximum
+    }
+
+    /// Topic aliases an MQTT 5 client may use in its PUBLISH packets
+    pub fn topic_alias_maximum(&self) -> u16 {
+        self.topic_alias_maximum
+    }
+
+    /// What an MQTT 5 CONNACK tells the client about unavailable features
+    pub fn connack_properties(&self) -> Properties {
+        let unavailable = |enabled: bool| if enabled { None } else { Some(0) };
+        Properties {
+            retain_available: unavailable(self.retain_enabled),
+            wildcard_subscription_available: unavailable(self.wildcards_enabled),
+            subscription_identifier_available: unavailable(self.subscription_identifiers_enabled),
+            receive_maximum: match self.receive_maximum {
+                65535 => None,
+                maximum => Some(maximum),
+            },
+            topic_alias_maximum: match self.topic_alias_maximum {
+                0 => None,
+                maximum => Some(maximum),
+            },
+            shared_subscription_available: unavailable(self.shared_enabled),
+            maximum_packet_size: if self.max_packet_size < MAX_PACKET_SIZE {
+                Some(self.max_packet_size)
+            } else {
+                None
+            },
+            ..Properties::default()
+        }
+    }
+
+    /* Connections */
+    pub fn open(&mut self, peer: SocketAddr, tx: UnboundedSender<Bytes>) {
+        self.connections.insert(peer, tx);
+    }
+
+    /// Picks an unspecified address for a local client, one no connection has
+    pub fn reserve_local_peer(&mut self) -> Option<SocketAddr> {
+        let peer = (1..=65535)
+            .map(|port| SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
+            .find(|peer| !self.local_peers.contains(peer) && !self.connections.contains_key(peer))?;
+        self.local_peers.insert(peer);
+        Some(peer)
+    }
+
+    pub fn release_local_peer(&mut self, peer: &SocketAddr) {
+        self.local_peers.remove(peer);
+    }
+
+    /// Whether the connection is still attached to the session of `client_id`
+    pub fn is_attached(&self, peer: &SocketAddr, client_id: &str) -> bool {
+        self.clients.get(peer).map(|c| c.as_str()) == Some(client_id)
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn send(&self, peer: &SocketAddr, packet: Bytes) {
+        // The queue is gone once the connection is closing
+        if let Some(tx) = self.connections.get(peer) {
+            let _ = tx.unbounded_send(packet);
+        }
+    }
+
+    /// Without its queue the writer sends what is left and finishes
+    pub fn close(&mut self, peer: &SocketAddr) {
+        self.connections.remove(peer);
+    }
+
+    /// Tells an MQTT 5 client why the server ends the connection, then closes it
+    fn close_with(&mut self, peer: &SocketAddr, version: ProtocolVersion, reason_code: ReasonCode) {
+        if version == ProtocolVersion::V5 {
+            self.send(peer, writer::v5::disconnect(reason_code, &Properties::default()));
+        }
+        self.close(peer);
+    }
+
+    /// Peer and protocol version of every connection attached to a session
+    fn attached_connections(&self) -> Vec<(SocketAddr, ProtocolVersion)> {
+        self.sessions
+            .values()
+            .filter(|session| session.is_online(&self.connections))
+            .filter_map(|session| session.peer.map(|peer| (peer, session.version)))
+            .collect()
+    }
+
+    /// Notes that a packet arrived, for the Keep Alive
+    pub fn touch(&mut self, peer: &SocketAddr) {
+        let sessions = &mut self.sessions;
+        if let Some(session) = self.clients.get(peer).and_then(|id| sessions.get_mut(id)) {
+            session.last_packet = Instant::now();
+        }
+    }
+
+    /// Called once a connection is gone, publishes the will unless it was discarded.
+    /// A delayed will waits for the client to come back, at most until the session ends.
+    pub fn disconnect(&mut self, peer: &SocketAddr) {
+        self.connections.remove(peer);
+        let client_id = match self.clients.remove(peer) {
+            Some(id) => id,
+            None => return,
+        };
+        let (will, expiry_interval, mut inflight, queued) = match self.sessions.get_mut(&client_id) {
+            Some(session) => {
+                session.peer = None;
+                session.disconnected_at = Some(Instant::now());
+                let inflight: Vec<(u16, (String, Message))> = session.shared_inflight.drain().collect();
+                for &(id, _) in &inflight {
+                    session.inflight.remove(&id);
+                }
+                let (queued, own): (VecDeque<_>, VecDeque<_>) =
+                    session.queue.drain(..).partition(|(_, shared)| shared.is_some());
+                session.queue = own;
+                (session.will.take(), session.expiry_interval, inflight, queued)
+            }
+            None => return,
+        };
+        if expiry_interval == 0 {
+            self.sessions.remove(&client_id);
+        }
+        self.leave_shared_groups(&client_id, None);
+        // What the member did not acknowledge or got no chance to receive goes to the rest of its group
+        inflight.sort_by_key(|&(id, _)| id);
+        let inflight = inflight.into_iter().map(|(_, (filter, message))| (message, filter));
+        let queued = queued.into_iter().map(|(message, filter)| (message, filter.unwrap()));
+        for (message, filter) in inflight.chain(queued) {
+            self.deliver_shared(&filter, &message);
+        }
+        let will = match will {
+            Some(will) => will,
+            None => return,
+        };
+        match will.delay_interval.min(expiry_interval) {
+            0 => {
+                self.publish(will.message);
+            }
+            delay => {
+                let at = Instant::now() + Duration::from_secs(delay as u64);
+                self.sessions.get_mut(&client_id).unwrap().delayed_will = Some((will.message, at));
+            }
+        }
+    }
+
+    /// Closes every connection, dropping the wills unless they should go out
+    pub fn shutdown(&mut self, publish_wills: bool) {
+        let mut wills = Vec::new();
+        for session in self.sessions.values_mut() {
+            match session.will {
+                // Nobody would be around once the delay passes
+                Some(ref mut will) if publish_wills => will.delay_interval = 0,
+                _ => session.will = None,
+            }
+            if let Some((will, _)) = session.delayed_will.take() {
+                if publish_wills {
+                    wills.push(will);
+                }
+            }
+        }
+        for will in wills {
+            self.publish(will);
+        }
+        for (peer, version) in self.attached_connections() {
+            self.close_with(&peer, version, ReasonCode::ServerShuttingDown);
+        }
+        self.connections.clear();
+    }
+
+    /* Redirection */
+
+    /// Reason code and Server Reference for clients while they are sent elsewhere
+    pub fn redirection(&self) -> Option<(ReasonCode, &str)> {
+        let code = if self.redirect.permanent {
+            ReasonCode::ServerMoved
+        } else {
+            ReasonCode::UseAnotherServer
+        };
+        match self.redirect.server_reference {
+            Some(ref reference) if self.redirecting => Some((code, reference)),
+            _ => None,
+        }
+    }
+
+    /// Starts or stops sending clients to the configured server. Connected clients
+    /// are disconnected on start if configured so.
+    pub fn toggle_redirect(&mut self) {
+        if self.redirect.server_reference.is_none() {
+            warn!("Cannot redirect clients without redirect.server_reference");
+            return;
+        }
+        self.redirecting = !self.redirecting;
+        let (code, reference) = match self.redirection() {
+            Some((code, reference)) => (code, reference.to_string()),
+            None => {
+                info!("Stopped redirecting clients");
+                return;
+            }
+        };
+        info!("Redirecting clients to {}", reference);
+        if !self.redirect.disconnect_clients {
+            return;
+        }
+        let properties = Properties {
+            server_reference: Some(reference),
+            ..Properties::default()
+        };
+        for (peer, version) in self.attached_connections() {
+            if version == ProtocolVersion::V5 {
+                self.send(&peer, writer::v5::disconnect(code, &properties));
+            }
+            self.close(&peer);
+        }
+    }
+
+    /* Sessions */
+
+    /// Binds the client id to the connection, taking it over from any other
+    /// connection. The session outlives the connection by `expiry_interval` seconds,
+    /// `clean_start` discards the previous one. Returns whether a previous session was resumed.
+    pub fn attach(
+        &mut self,
+        identity: &ClientIdentity,
+        version: ProtocolVersion,
+        clean_start: bool,
+        expiry_interval: u32,
+        will: Option<Will>,
+    ) -> bool {
+        let client_id = &identity.client_id;
+        let previous = self.sessions.get(client_id).and_then(|s| s.peer.map(|p| (p, s.version)));
+        if let Some((previous, previous_version)) = previous {
+            info!("Client {:?} took over the connection from {}", client_id, previous);
+            self.close_with(&previous, previous_version, ReasonCode::SessionTakenOver);
+            self.disconnect(&previous);
+        }
+        // Coming back in time suppresses a delayed will, unless the session ends here
+        let delayed_will = self.sessions.get_mut(client_id).and_then(|s| s.delayed_will.take());
+        if let Some((will, _)) = delayed_will {
+            if clean_start {
+                self.publish(will);
+            }
+        }
+        let resumed = !clean_start && self.sessions.contains_key(client_id);
+        if !resumed {
+            let session = Session::new(identity.peer, version, expiry_interval);
+            self.sessions.insert(client_id.clone(), session);
+        }
+        let session = self.sessions.get_mut(client_id).unwrap();
+        session.peer = Some(identity.peer);
+        session.version = version;
+        session.expiry_interval = expiry_interval;
+        session.disconnected_at = None;
+        session.limits = ClientLimits::default();
+        session.aliases = OutboundAliases::new(0);
+        session.keep_alive = 0;
+        session.last_packet = Instant::now();
+        session.will = will;
+        session.response_topic = None;
+        self.clients.insert(identity.peer, client_id.clone());
+        resumed
+    }
+
+    /// Sends again what the client did not acknowledge, then what was queued while it was offline
+    pub fn resume(&mut self, client_id: &str) {
+        if let Some(session) = self.sessions.get_mut(client_id) {
+            session.retransmit(&self.connections);
+            session.flush(&self.connections);
+        }
+    }
+
+    /// MQTT 5 clients may change the interval when disconnecting
+    pub fn set_session_expiry(&mut self, client_id: &str, expiry_interval: u32) {
+        if let Some(session) = self.sessions.get_mut(client_id) {
+            session.expiry_interval = expiry_interval;
+        }
+    }
+
+    /// Applies what an MQTT 5 client announced in CONNECT to what is sent to it
+    pub fn set_client_limits(&mut self, client_id: &str, limits: ClientLimits) {
+        if let Some(session) = self.sessions.get_mut(client_id) {
+            session.aliases = OutboundAliases::new(limits.topic_alias_maximum);
+            session.limits = limits;
+        }
+    }
+
+    /// Connections without a packet for one and a half times `keep_alive` seconds are closed
+    pub fn set_keep_alive(&mut self, client_id: &str, keep_alive: u16) {
+        if let Some(session) = self.sessions.get_mut(client_id) {
+            session.keep_alive = keep_alive;
+        }
+    }
+
+    /// Publishes delayed wills and closes connections past their Keep Alive,
+    /// then drops expired sessions, queued messages and retained messages. Called periodically.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let mut wills = Vec::new();
+        let mut timed_out = Vec::new();
+        for (client_id, session) in self.sessions.iter_mut() {
+            if session.delayed_will.as_ref().is_some_and(|&(_, at)| at <= now) {
+                wills.push(session.delayed_will.take().unwrap().0);
+            }
+            match session.peer {
+                Some(peer) if session.is_online(&self.connections) && session.is_timed_out(now) => {
+                    warn!("Client {:?} exceeded its Keep Alive of {}s", client_id, session.keep_alive);
+                    timed_out.push((peer, session.version));
+                }
+                _ => {}
+            }
+        }
+        for will in wills {
+            self.publish(will);
+        }
+        for (peer, version) in timed_out {
+            self.close_with(&peer, version, ReasonCode::KeepAliveTimeout);
+        }
+
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|&(_, session)| session.is_expired(now))
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in expired {
+            info!("Session of client {:?} expired", client_id);
+            self.sessions.remove(&client_id);
+            self.leave_shared_groups(&client_id, None);
+        }
+        for session in self.sessions.values_mut() {
+            session.queue.retain(|(m, _)| !m.is_expired());
+        }
+        self.retained.retain(|_, m| !m.is_expired());
+    }
+
+    /// Drops messages a session queued over the limit. A client which is online
+    /// but not keeping up is disconnected.
+    fn enforce_queue_limit(&mut self, client_id: &str) {
+        let limit = self.max_queued_messages;
+        let online = match self.sessions.get_mut(client_id) {
+            Some(ref mut session) if limit > 0 && session.queue.len() > limit => {
+                session.queue.truncate(limit);
+                match session.peer {
+                    Some(peer) if session.is_online(&self.connections) => Some((peer, session.version)),
+                    _ => None,
+                }
+            }
+            _ => return,
+        };
+        match online {
+            Some((peer, version)) => {
+                warn!("Disconnecting client {:?}: more than {} messages queued", client_id, limit);
+                self.close_with(&peer, version, ReasonCode::QuotaExceeded);
+            }
+            None => debug!("Dropped a message to client {:?}: its queue is full", client_id),
+        }
+    }
+
+    /// Forgets the will after a clean DISCONNECT
+    pub fn discard_will(&mut self, client_id: &str) {
+        if let Some(session) = self.sessions.get_mut(client_id) {
+            session.will = None;
+        }
+    }
+
+    /// Returns the granted QoS or why the subscription was refused
+    pub fn subscribe(
+        &mut self,
+        client_id: &str,
+        filter: &str,
+        options: &SubscriptionOptions,
+    ) -> Result<QoS, ReasonCode> {
+        if !self.wildcards_enabled && topic::has_wildcards(filter) {
+            return Err(ReasonCode::WildcardSubscriptionsNotSupported);
+        }
+        if !self.shared_enabled && topic::split_shared(filter).is_some() {
+            return Err(ReasonCode::SharedSubscriptionsNotSupported);
+        }
+        match self.sessions.get_mut(client_id) {
+            Some(session) => {
+                session.subscriptions.insert(filter.to_string(), options.clone());
+                Ok(options.qos.clone())
+            }
+            None => Err(ReasonCode::UnspecifiedError),
+        }
+    }
+
+    pub fn is_subscribed(&self, client_id: &str, filter: &str) -> bool {
+        self.sessions.get(client_id).is_some_and(|s| s.subscriptions.contains_key(filter))
+    }
+
+    /// Returns whether there was such a subscription
+    pub fn unsubscribe(&mut self, client_id: &str, filter: &str) -> bool {
+        let removed = match self.sessions.get_mut(client_id) {
+            Some(session) => session.subscriptions.remove(filter).is_some(),
+            None => false,
+        };
+        if removed && topic::split_shared(filter).is_some() {
+            self.leave_shared_groups(client_id, Some(filter));
+        }
+        removed
+    }
+
+    /// Forgets the sticky assignments to and from a client which left, or only those
+    /// to it in the group of `filter` it unsubscribed from, and groups nobody is in anymore
+    fn leave_shared_groups(&mut self, client_id: &str, filter: Option<&str>) {
+        let sessions = &self.sessions;
+        self.shared_groups.retain(|group_filter, group| {
+            match filter {
+                Some(filter) if filter != group_filter => {}
+                Some(_) => group.sticky.retain(|_, member| member != client_id),
+                None => group.sticky.retain(|sender, member| sender != client_id && member != client_id),
+            }
+            sessions.values().any(|session| session.subscriptions.contains_key(group_filter))
+        });
+    }
+
+    /// Client received a QoS 2 message with PUBREC, it only waits for PUBREL now
+    pub fn release(&mut self, client_id: &str, packet_id: u16) {
+        if let Some(message) = self.sessions.get_mut(client_id).and_then(|s| s.inflight.get_mut(&packet_id)) {
+            *message = None;
+        }
+    }
+
+    /// Client finished receiving a QoS 1 or 2 message with PUBACK, PUBCOMP
+    /// or a PUBREC refusing it, so the next queued one can go out
+    pub fn acknowledge(&mut self, client_id: &str, packet_id: u16) {
+        if let Some(session) = self.sessions.get_mut(client_id) {
+            session.inflight.remove(&packet_id);
+            session.shared_inflight.remove(&packet_id);
+            session.flush(&self.connections);
+        }
+    }
+
+    /// Sends the retained messages matching a new subscription, shared ones get none
+    pub fn send_retained(&mut self, client_id: &str, filter: &str) {
+        if topic::split_shared(filter).is_some() {
+            return;
+        }
+        let session = match self.sessions.get_mut(client_id) {
+            Some(s) => s,
+            None => return,
+        };
+        let options = match session.subscriptions.get(filter) {
+            Some(options) => options.clone(),
+            None => return,
+        };
+        for message in self.retained.values() {
+            if topic::matches(filter, &message.topic) && !message.is_expired() {
+                // Sent because of the subscription, so the RETAIN flag stays
+                let message = Message { retain: true, ..delivered(message, &options) };
+                session.send(&self.connections, &message);
+            }
+        }
+        self.enforce_queue_limit(client_id);
+    }
+
+    /* Routing */
+
+    /// Returns the number of sessions the message was routed to
+    pub fn publish(&mut self, mut message: Message) -> usize {
+        if message.expiry.is_none() {
+            let interval = message.properties.message_expiry_interval;
+            message.expiry = interval.map(|seconds| Instant::now() + Duration::from_secs(seconds as u64));
+        }
+        if message.retain && self.retain_enabled {
+            if message.payload.is_empty() {
+                self.retained.remove(&message.topic);
+            } else {
+                self.retained.insert(message.topic.clone(), message.clone());
+            }
+        }
+        let mut matched = 0;
+        let mut shared = Vec::new();
+        let mut queued = Vec::new();
+        {
+            let connections = &self.connections;
+            let limit = self.max_queued_messages;
+            for (client_id, session) in self.sessions.iter_mut() {
+                if let Some((options, identifiers)) = session.combined_options(client_id, &message) {
+                    let mut delivered = delivered(&message, &options);
+                    delivered.properties.subscription_identifiers = identifiers;
+                    session.send(connections, &delivered);
+                    matched += 1;
+                    if limit > 0 && session.queue.len() > limit {
+                        queued.push(client_id.clone());
+                    }
+                }
+                for filter in session.subscriptions.keys() {
+                    match topic::split_shared(filter) {
+                        Some((_, inner)) if topic::matches(inner, &message.topic) && !shared.contains(filter) => {
+                            shared.push(filter.clone());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        for client_id in queued {
+            self.enforce_queue_limit(&client_id);
+        }
+        for filter in shared {
+            if self.deliver_shared(&filter, &message) {
+                matched += 1;
+            }
+        }
+        matched
+    }
+
+    /// Sends the message to one member of a shared subscription, preferring
+    /// connected ones. Returns false if the subscription has no members left.
+    fn deliver_shared(&mut self, filter: &str, message: &Message) -> bool {
+        let mut members: Vec<(String, SubscriptionOptions, bool)> = self
+            .sessions
+            .iter()
+            .filter_map(|(client_id, session)| {
+                session.subscriptions.get(filter).map(|options| {
+                    (client_id.clone(), options.clone(), session.is_online(&self.connections))
+                })
+            })
+            .collect();
+        if members.is_empty() {
+            return false;
+        }
+        // Offline members only get messages when nobody else is around to take them
+        if members.iter().any(|&(_, _, online)| online) {
+            members.retain(|&(_, _, online)| online);
+        }
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let group = self
+            .shared_groups
+            .entry(filter.to_string())
+            .or_default();
+        let sticky = match (self.shared_strategy, &message.sender) {
+            (SharedStrategy::Sticky, Some(sender)) => group
+                .sticky
+                .get(sender)
+                .and_then(|member| members.iter().position(|m| m.0 == *member)),
+            _ => None,
+        };
+        let index = match sticky {
+            Some(index) => index,
+            None => {
+                let index = group.next % members.len();
+                group.next = group.next.wrapping_add(1);
+                if let (SharedStrategy::Sticky, Some(sender)) = (self.shared_strategy, &message.sender) {
+                    group.sticky.insert(sender.clone(), members[index].0.clone());
+                }
+                index
+            }
+        };
+
+        let (ref client_id, ref granted, _) = members[index];
+        {
+            let session = self.sessions.get_mut(client_id).unwrap();
+            session.send_shared(&self.connections, &delivered(message, granted), Some(filter));
+        }
+        self.enforce_queue_limit(client_id);
+        true
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use broker::*;
+    use futures::{Future, Stream};
+    use futures::sync::mpsc::{self, UnboundedReceiver};
+    use mqtt::reader::*;
+
+    fn options(qos: QoS) -> SubscriptionOptions {
+        SubscriptionOptions::new(qos)
+    }
+
+    fn connect(broker: &mut Broker, client_id: &str, port: u16, clean: bool) -> UnboundedReceiver<Bytes> {
+        let peer: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        broker.open(peer, tx);
+        let identity = ClientIdentity {
+            client_id: client_id.to_string(),
+            username: None,
+            peer: peer,
+            topics: None,
+        };
+        let expiry_interval = if clean { 0 } else { NEVER_EXPIRES };
+        broker.attach(&identity, ProtocolVersion::V311, clean, expiry_interval, None);
+        rx
+    }
+
+    fn message(topic: &str, qos: QoS, retain: bool) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: Bytes::from(&b"data"[..]),
+            qos: qos,
+            retain: retain,
+            properties: Properties::default(),
+            sender: None,
+            expiry: None,
+        }
+    }
+
+    fn received(rx: UnboundedReceiver<Bytes>, count: u64) -> Vec<MqttPacket> {
+        let packets = rx.take(count).collect().wait().unwrap();
+        packets.into_iter().map(|p| read_packet(p).unwrap()).collect()
+    }
+
+    #[test]
+    fn routes_to_matching_subscriptions() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let rx = connect(&mut broker, "sub", 1, true);
+        assert_eq!(broker.subscribe("sub", "sensors/+", &options(QoS::AtLeastOnce)), Ok(QoS::AtLeastOnce));
+        broker.publish(message("other/1", QoS::AtLeastOnce, false));
+        broker.publish(message("sensors/1", QoS::ExactlyOnce, false));
+        broker.publish(message("sensors/2", QoS::AtMostOnce, false));
+
+        let packets = received(rx, 2);
+        assert_eq!(packets[0].header.qos, QoS::AtLeastOnce);
+        assert_eq!(packets[0].var_header, VariableHeader::Publish(PublishHeader {
+            topic_name: "sensors/1".to_string(),
+            packet_id: 1,
+        }));
+        assert_eq!(packets[1].header.qos, QoS::AtMostOnce);
+    }
+
+    #[test]
+    fn sends_retained_messages_on_subscribe() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        broker.publish(message("a/b", QoS::AtLeastOnce, true));
+        broker.publish(message("a/c", QoS::AtLeastOnce, true));
+        broker.publish(Message { payload: Bytes::new(), ..message("a/c", QoS::AtMostOnce, true) });
+
+        let rx = connect(&mut broker, "sub", 1, true);
+        assert_eq!(broker.subscribe("sub", "a/#", &options(QoS::AtMostOnce)), Ok(QoS::AtMostOnce));
+        broker.send_retained("sub", "a/#");
+        broker.close(&"127.0.0.1:1".parse().unwrap());
+
+        let packets = received(rx, 2);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].header.retain, true);
+        assert_eq!(packets[0].header.qos, QoS::AtMostOnce);
+    }
+
+    #[test]
+    fn applies_subscription_options() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let rx = connect(&mut broker, "sub", 1, true);
+        let no_local = SubscriptionOptions { no_local: true, ..options(QoS::AtMostOnce) };
+        broker.subscribe("sub", "a", &no_local).unwrap();
+        let retain_as_published = SubscriptionOptions { retain_as_published: true, ..options(QoS::AtMostOnce) };
+        broker.subscribe("sub", "b", &retain_as_published).unwrap();
+        let own = |topic| Message { sender: Some("sub".to_string()), ..message(topic, QoS::AtMostOnce, true) };
+        assert_eq!(broker.publish(own("a")), 0);
+        assert_eq!(broker.publish(message("a", QoS::AtMostOnce, true)), 1);
+        assert_eq!(broker.publish(own("b")), 1);
+
+        let packets = all_received(&mut broker, rx, 1);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].header.retain, false);
+        assert_eq!(packets[1].header.retain, true);
+    }
+
+    struct DenyAll;
+
+    impl Authorizer for DenyAll {
+        fn authorize(&self, _: &ClientIdentity, _: &str, _: &QoS, _: Action) -> bool {
+            false
+        }
+    }
+
+    fn identity(client_id: &str, username: Option<&str>, port: u16) -> ClientIdentity {
+        ClientIdentity {
+            client_id: client_id.to_string(),
+            username: username.map(|u| u.to_string()),
+            peer: format!("127.0.0.1:{}", port).parse().unwrap(),
+            topics: None,
+        }
+    }
+
+    /// Attaches an MQTT 5 connection for the identity, returning its outbound queue
+    fn attach(broker: &mut Broker, identity: &ClientIdentity, clean: bool) -> UnboundedReceiver<Bytes> {
+        let (tx, rx) = mpsc::unbounded();
+        broker.open(identity.peer, tx);
+        broker.attach(identity, ProtocolVersion::V5, clean, NEVER_EXPIRES, None);
+        rx
+    }
+
+    #[test]
+    fn builds_with_replaced_auth_sources() {
+        let mut limits = Config::default().limits;
+        limits.receive_maximum = 0;
+        assert!(Broker::builder().limits(limits).build().is_err());
+
+        let broker = Broker::builder().authorizer(Box::new(DenyAll)).build().unwrap();
+        let identity = ClientIdentity {
+            client_id: "c1".to_string(),
+            username: None,
+            peer: "127.0.0.1:1".parse().unwrap(),
+            topics: None,
+        };
+        assert!(!broker.authorize(&identity, "a", &QoS::AtMostOnce, Action::Publish));
+    }
+
+    #[test]
+    fn requires_scram_from_a_replaced_authenticator() {
+        let path = env::temp_dir().join(format!("picomq-enhanced-auth-{}", process::id()));
+        fs::write(&path, "").unwrap();
+        let mut config = Config::default();
+        config.auth.password_file = Some(path.clone());
+        config.auth.require_enhanced_auth = true;
+        assert!(Broker::builder().config(config.clone()).build().is_ok());
+        let built = Broker::builder().config(config).authenticator(Box::new(AllowAll)).build();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(built.err(), Some("auth.require_enhanced_auth needs an authenticator offering SCRAM".to_string()));
+    }
+
+    #[test]
+    fn allows_clients_their_response_topics() {
+        let mut config = Config::default();
+        assert_eq!(Broker::new(&config).unwrap().grant_response_topic(&identity("c1", Some("alice"), 1)), None);
+        config.features.response_topic_prefix = "response".to_string();
+        let mut broker = Broker::new(&config).unwrap();
+        broker.set_authorizer(Box::new(DenyAll));
+        let alice = identity("c1", Some("alice"), 1);
+        attach(&mut broker, &alice, false);
+        // Nothing is granted before the client asks for it
+        assert!(!broker.authorize(&alice, "response/alice/c1/42", &QoS::AtMostOnce, Action::Publish));
+        assert_eq!(broker.grant_response_topic(&alice), Some("response/alice/c1".to_string()));
+        assert!(broker.authorize(&alice, "response/alice/c1/#", &QoS::AtMostOnce, Action::Subscribe));
+        assert!(broker.authorize(&alice, "response/alice/c1/42", &QoS::AtMostOnce, Action::Publish));
+        assert!(!broker.authorize(&alice, "response/alice/c10", &QoS::AtMostOnce, Action::Publish));
+        assert!(!broker.authorize(&alice, "response/alice/c2/42", &QoS::AtMostOnce, Action::Publish));
+        let limited = ClientIdentity { topics: Some(TopicGrants::default()), ..alice.clone() };
+        assert!(!broker.authorize(&limited, "response/alice/c1/42", &QoS::AtMostOnce, Action::Publish));
+
+        // Taking the client id over gets another user neither alice's topic nor one of its own
+        let mallory = identity("c1", Some("mallory"), 2);
+        attach(&mut broker, &mallory, false);
+        assert!(!broker.authorize(&mallory, "response/alice/c1/42", &QoS::AtMostOnce, Action::Publish));
+        assert_eq!(broker.grant_response_topic(&mallory), Some("response/mallory/c1".to_string()));
+        assert!(!broker.authorize(&mallory, "response/alice/c1/42", &QoS::AtMostOnce, Action::Publish));
+        assert!(!broker.authorize(&alice, "response/alice/c1/42", &QoS::AtMostOnce, Action::Publish));
+        assert_eq!(broker.grant_response_topic(&identity("c1", None, 2)), None);
+        assert_eq!(broker.grant_response_topic(&identity("c1", Some("a/b"), 2)), None);
+    }
+
+    #[test]
+    fn queues_messages_for_offline_sessions() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        connect(&mut broker, "sub", 1, false);
+        assert_eq!(broker.subscribe("sub", "a", &options(QoS::AtLeastOnce)), Ok(QoS::AtLeastOnce));
+        broker.disconnect(&"127.0.0.1:1".parse().unwrap());
+        broker.publish(message("a", QoS::AtLeastOnce, false));
+        broker.publish(message("a", QoS::AtMostOnce, false));
+
+        let rx = connect(&mut broker, "sub", 2, false);
+        broker.resume("sub");
+        broker.close(&"127.0.0.1:2".parse().unwrap());
+        assert_eq!(received(rx, 2).len(), 1);
+    }
+
+    #[test]
+    fn resends_unacknowledged_messages_on_resume() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        connect(&mut broker, "sub", 1, false);
+        broker.subscribe("sub", "a", &options(QoS::ExactlyOnce)).unwrap();
+        broker.publish(message("a", QoS::AtLeastOnce, false));
+        broker.publish(message("a", QoS::ExactlyOnce, false));
+        broker.publish(message("a", QoS::ExactlyOnce, false));
+        // The second one was received, the third one was acknowledged completely
+        broker.release("sub", 2);
+        broker.acknowledge("sub", 3);
+        broker.disconnect(&"127.0.0.1:1".parse().unwrap());
+
+        let rx = connect(&mut broker, "sub", 2, false);
+        broker.resume("sub");
+        broker.close(&"127.0.0.1:2".parse().unwrap());
+        let packets = received(rx, 3);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].header.dup, true);
+        assert_eq!(packets[0].header.qos, QoS::AtLeastOnce);
+        assert_eq!(packets[0].var_header, VariableHeader::Publish(PublishHeader {
+            topic_name: "a".to_string(),
+            packet_id: 1,
+        }));
+        assert_eq!(packets[1].header.packet_type, PacketType::PubRel);
+        assert_eq!(packets[1].var_header, VariableHeader::WithPacketId(2));
+    }
+
+    #[test]
+    fn drops_expired_messages() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        connect(&mut broker, "sub", 1, false);
+        broker.subscribe("sub", "a", &options(QoS::AtLeastOnce)).unwrap();
+        broker.disconnect(&"127.0.0.1:1".parse().unwrap());
+        let expiring = |seconds| Message {
+            properties: Properties {
+                message_expiry_interval: Some(seconds),
+                ..Properties::default()
+            },
+            ..message("a", QoS::AtLeastOnce, true)
+        };
+        broker.publish(expiring(60));
+        broker.publish(Message { expiry: Some(Instant::now()), retain: false, ..expiring(60) });
+        broker.expire();
+        assert_eq!(broker.sessions["sub"].queue.len(), 1);
+        assert_eq!(broker.retained.len(), 1);
+
+        let peer: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        broker.open(peer, tx);
+        let identity = ClientIdentity::new(&ConnectPayload { client_id: "sub".to_string(), ..ConnectPayload::default() }, &peer);
+        broker.attach(&identity, ProtocolVersion::V5, false, NEVER_EXPIRES, None);
+        broker.resume("sub");
+        broker.close(&peer);
+        let packets = rx.collect().wait().unwrap();
+        let packet = read_packet_with_version(packets[0].clone(), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet.properties.message_expiry_interval, Some(60));
+    }
+
+    #[test]
+    fn expires_disconnected_sessions() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        connect(&mut broker, "kept", 1, false);
+        connect(&mut broker, "expiring", 2, false);
+        broker.set_session_expiry("expiring", 10);
+        broker.disconnect(&"127.0.0.1:1".parse().unwrap());
+        broker.disconnect(&"127.0.0.1:2".parse().unwrap());
+        broker.expire();
+        assert_eq!(broker.sessions.len(), 2);
+
+        let long_ago = Instant::now() - Duration::from_secs(3600);
+        for session in broker.sessions.values_mut() {
+            session.disconnected_at = Some(long_ago);
+        }
+        broker.expire();
+        assert!(broker.sessions.contains_key("kept"));
+        assert!(!broker.sessions.contains_key("expiring"));
+    }
+
+    fn all_received(broker: &mut Broker, rx: UnboundedReceiver<Bytes>, port: u16) -> Vec<MqttPacket> {
+        broker.close(&format!("127.0.0.1:{}", port).parse().unwrap());
+        let packets = rx.collect().wait().unwrap();
+        packets.into_iter().map(|p| read_packet(p).unwrap()).collect()
+    }
+
+    #[test]
+    fn balances_shared_subscriptions() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let rx1 = connect(&mut broker, "w1", 1, true);
+        let rx2 = connect(&mut broker, "w2", 2, true);
+        for worker in &["w1", "w2"] {
+            broker.subscribe(worker, "$share/workers/jobs/+", &options(QoS::AtLeastOnce)).unwrap();
+        }
+        for _ in 0..4 {
+            assert_eq!(broker.publish(message("jobs/1", QoS::AtLeastOnce, false)), 1);
+        }
+        assert_eq!(all_received(&mut broker, rx1, 1).len(), 2);
+        assert_eq!(all_received(&mut broker, rx2, 2).len(), 2);
+    }
+
+    #[test]
+    fn sticks_to_a_member_per_publisher() {
+        let mut config = Config::default();
+        config.features.shared_subscription_strategy = SharedStrategy::Sticky;
+        let mut broker = Broker::new(&config).unwrap();
+        let rx1 = connect(&mut broker, "w1", 1, true);
+        let rx2 = connect(&mut broker, "w2", 2, true);
+        for worker in &["w1", "w2"] {
+            broker.subscribe(worker, "$share/workers/jobs", &options(QoS::AtMostOnce)).unwrap();
+        }
+        for sender in &["a", "a", "b", "a", "b"] {
+            let sender = Some(sender.to_string());
+            broker.publish(Message { sender: sender, ..message("jobs", QoS::AtMostOnce, false) });
+        }
+        assert_eq!(all_received(&mut broker, rx1, 1).len(), 3);
+        assert_eq!(all_received(&mut broker, rx2, 2).len(), 2);
+    }
+
+    #[test]
+    fn redistributes_unacknowledged_shared_messages() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let rx1 = connect(&mut broker, "w1", 1, true);
+        let rx2 = connect(&mut broker, "w2", 2, true);
+        for worker in &["w1", "w2"] {
+            broker.subscribe(worker, "$share/workers/jobs", &options(QoS::AtLeastOnce)).unwrap();
+        }
+        broker.publish(message("jobs", QoS::AtLeastOnce, false));
+        broker.publish(message("jobs", QoS::AtLeastOnce, false));
+        broker.publish(message("jobs", QoS::AtLeastOnce, false));
+        // w1 got the first and third message and only acknowledges the first
+        broker.acknowledge("w1", 1);
+        broker.disconnect(&"127.0.0.1:1".parse().unwrap());
+
+        assert_eq!(rx1.collect().wait().unwrap().len(), 2);
+        assert_eq!(all_received(&mut broker, rx2, 2).len(), 2);
+    }
+
+    #[test]
+    fn redistributes_queued_shared_messages() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let rx1 = connect(&mut broker, "w1", 1, true);
+        let rx2 = connect(&mut broker, "w2", 2, true);
+        let limits = ClientLimits { receive_maximum: 1, ..ClientLimits::default() };
+        broker.set_client_limits("w1", limits);
+        for worker in &["w1", "w2"] {
+            broker.subscribe(worker, "$share/workers/jobs", &options(QoS::AtLeastOnce)).unwrap();
+        }
+        for _ in 0..6 {
+            broker.publish(message("jobs", QoS::AtLeastOnce, false));
+        }
+        // w1 has one in flight and two waiting behind its Receive Maximum
+        assert_eq!(broker.sessions["w1"].queue.len(), 2);
+        broker.disconnect(&"127.0.0.1:1".parse().unwrap());
+
+        assert_eq!(rx1.collect().wait().unwrap().len(), 1);
+        assert_eq!(all_received(&mut broker, rx2, 2).len(), 6);
+    }
+
+    #[test]
+    fn forgets_shared_groups_members_left() {
+        let mut config = Config::default();
+        config.features.shared_subscription_strategy = SharedStrategy::Sticky;
+        let mut broker = Broker::new(&config).unwrap();
+        let _rx1 = connect(&mut broker, "w1", 1, true);
+        let _rx2 = connect(&mut broker, "w2", 2, false);
+        for worker in &["w1", "w2"] {
+            broker.subscribe(worker, "$share/workers/jobs", &options(QoS::AtMostOnce)).unwrap();
+            broker.subscribe(worker, "$share/other/jobs", &options(QoS::AtMostOnce)).unwrap();
+        }
+        for sender in &["a", "b", "c", "d"] {
+            let sender = Some(sender.to_string());
+            broker.publish(Message { sender: sender, ..message("jobs", QoS::AtMostOnce, false) });
+        }
+        assert_eq!(broker.shared_groups["$share/workers/jobs"].sticky.len(), 4);
+
+        broker.unsubscribe("w1", "$share/workers/jobs");
+        assert_eq!(broker.shared_groups["$share/workers/jobs"].sticky.len(), 2);
+        assert_eq!(broker.shared_groups["$share/other/jobs"].sticky.len(), 4);
+        broker.disconnect(&"127.0.0.1:1".parse().unwrap());
+        assert_eq!(broker.shared_groups["$share/other/jobs"].sticky.len(), 2);
+        broker.unsubscribe("w2", "$share/workers/jobs");
+        assert!(!broker.shared_groups.contains_key("$share/workers/jobs"));
+        // The offline session of w2 is still in the other group
+        broker.disconnect(&"127.0.0.1:2".parse().unwrap());
+        assert_eq!(broker.shared_groups["$share/other/jobs"].sticky.len(), 0);
+    }
+
+    #[test]
+    fn speaks_mqtt_5_to_mqtt_5_sessions() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        broker.open(peer, tx);
+        let identity = ClientIdentity {
+            client_id: "sub".to_string(),
+            username: None,
+            peer: peer,
+            topics: None,
+        };
+        broker.attach(&identity, ProtocolVersion::V5, true, 0, None);
+        broker.subscribe("sub", "a", &options(QoS::AtMostOnce)).unwrap();
+        let properties = Properties {
+            content_type: Some("text/plain".to_string()),
+            ..Properties::default()
+        };
+        assert_eq!(broker.publish(Message { properties: properties.clone(), ..message("a", QoS::AtMostOnce, false) }), 1);
+        assert_eq!(broker.publish(message("b", QoS::AtMostOnce, false)), 0);
+        // Another connection with the same client id takes the session over
+        connect(&mut broker, "sub", 2, true);
+
+        let packets: Vec<MqttPacket> = rx.collect().wait().unwrap()
+            .into_iter()
+            .map(|p| read_packet_with_version(p, ProtocolVersion::V5).unwrap())
+            .collect();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].properties, properties);
+        assert_eq!(packets[1].header.packet_type, PacketType::Disconnect);
+        assert_eq!(packets[1].reason_code, Some(ReasonCode::SessionTakenOver));
+    }
+
+    #[test]
+    fn aliases_frequent_topics_towards_mqtt_5_sessions() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        broker.open(peer, tx);
+        let identity = ClientIdentity {
+            client_id: "sub".to_string(),
+            username: None,
+            peer: peer,
+            topics: None,
+        };
+        broker.attach(&identity, ProtocolVersion::V5, true, 0, None);
+        broker.set_client_limits("sub", ClientLimits { topic_alias_maximum: 2, ..ClientLimits::default() });
+        broker.subscribe("sub", "sensors/#", &options(QoS::AtMostOnce)).unwrap();
+        for _ in 0..3 {
+            broker.publish(message("sensors/a", QoS::AtMostOnce, false));
+        }
+        broker.close(&peer);
+
+        let sent: Vec<(String, Option<u16>)> = rx.collect().wait().unwrap()
+            .into_iter()
+            .map(|p| read_packet_with_version(p, ProtocolVersion::V5).unwrap())
+            .map(|p| match p.var_header {
+                VariableHeader::Publish(h) => (h.topic_name, p.properties.topic_alias),
+                _ => panic!("Expected a PUBLISH"),
+            })
+            .collect();
+        assert_eq!(sent, vec![
+            ("sensors/a".to_string(), Option::None),
+            ("sensors/a".to_string(), Some(1)),
+            (String::new(), Some(1)),
+        ]);
+    }
+
+    #[test]
+    fn honors_client_receive_maximum_and_packet_size() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let rx = connect(&mut broker, "sub", 1, true);
+        let limits = ClientLimits { receive_maximum: 1, maximum_packet_size: 64, ..ClientLimits::default() };
+        broker.set_client_limits("sub", limits);
+        broker.subscribe("sub", "a", &options(QoS::AtLeastOnce)).unwrap();
+        let large = Message { payload: Bytes::from(vec![0; 64]), ..message("a", QoS::AtLeastOnce, false) };
+        broker.publish(message("a", QoS::AtLeastOnce, false));
+        broker.publish(large);
+        broker.publish(message("a", QoS::AtLeastOnce, false));
+        assert_eq!(broker.sessions["sub"].queue.len(), 2);
+        // The large message is skipped, which lets the last one through
+        broker.acknowledge("sub", 1);
+        assert_eq!(broker.sessions["sub"].queue.len(), 0);
+        assert_eq!(broker.sessions["sub"].inflight.len(), 1);
+
+        let packets = all_received(&mut broker, rx, 1);
+        assert_eq!(packets.len(), 2);
+        match packets[1].var_header {
+            VariableHeader::Publish(ref header) => assert_eq!(header.packet_id, 3),
+            _ => panic!("Expected a PUBLISH"),
+        }
+    }
+
+    #[test]
+    fn sends_subscription_identifiers_of_matching_subscriptions() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let rx = connect_v5(&mut broker, "sub", 1);
+        broker.publish(message("a/b", QoS::AtMostOnce, true));
+        let identified = |id| SubscriptionOptions { subscription_identifier: Some(id), ..options(QoS::AtMostOnce) };
+        broker.subscribe("sub", "a/+", &identified(1)).unwrap();
+        broker.send_retained("sub", "a/+");
+        broker.subscribe("sub", "a/b", &identified(2)).unwrap();
+        broker.subscribe("sub", "#", &options(QoS::AtMostOnce)).unwrap();
+        broker.publish(message("a/b", QoS::AtMostOnce, false));
+        broker.publish(message("c", QoS::AtMostOnce, false));
+        broker.close(&"127.0.0.1:1".parse().unwrap());
+
+        let identifiers: Vec<Vec<u32>> = rx.collect().wait().unwrap()
+            .into_iter()
+            .map(|p| read_packet_with_version(p, ProtocolVersion::V5).unwrap())
+            .map(|p| {
+                let mut identifiers = p.properties.subscription_identifiers;
+                identifiers.sort();
+                identifiers
+            })
+            .collect();
+        assert_eq!(identifiers, vec![vec![1], vec![1, 2], vec![]]);
+        assert_eq!(broker.connack_properties().subscription_identifier_available, Option::None);
+    }
+
+    #[test]
+    fn delays_wills_until_the_client_stays_away() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let rx = connect(&mut broker, "sub", 1, true);
+        broker.subscribe("sub", "status", &options(QoS::AtMostOnce)).unwrap();
+        let will = Will { message: message("status", QoS::AtMostOnce, false), delay_interval: 60 };
+        connect(&mut broker, "dev", 2, false);
+        broker.sessions.get_mut("dev").unwrap().will = Some(will.clone());
+        broker.disconnect(&"127.0.0.1:2".parse().unwrap());
+        broker.expire();
+        // Coming back in time suppresses the will
+        connect(&mut broker, "dev", 3, false);
+        broker.sessions.get_mut("dev").unwrap().will = Some(will);
+        broker.disconnect(&"127.0.0.1:3".parse().unwrap());
+        assert!(broker.sessions["dev"].delayed_will.is_some());
+        broker.sessions.get_mut("dev").unwrap().delayed_will.as_mut().unwrap().1 = Instant::now();
+        broker.expire();
+        assert_eq!(all_received(&mut broker, rx, 1).len(), 1);
+    }
+
+    fn connect_v5(broker: &mut Broker, client_id: &str, port: u16) -> UnboundedReceiver<Bytes> {
+        let rx = connect(broker, client_id, port, true);
+        broker.sessions.get_mut(client_id).unwrap().version = ProtocolVersion::V5;
+        rx
+    }
+
+    fn disconnect_reason(rx: UnboundedReceiver<Bytes>) -> Option<ReasonCode> {
+        let packets = rx.collect().wait().unwrap();
+        let packet = read_packet_with_version(packets.last().unwrap().clone(), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet.header.packet_type, PacketType::Disconnect);
+        packet.reason_code
+    }
+
+    #[test]
+    fn disconnects_clients_past_their_keep_alive_or_queue_limit() {
+        let mut config = Config::default();
+        config.limits.max_queued_messages = 2;
+        let mut broker = Broker::new(&config).unwrap();
+        let rx = connect_v5(&mut broker, "idle", 1);
+        broker.set_keep_alive("idle", 10);
+        broker.expire();
+        assert_eq!(broker.connection_count(), 1);
+        broker.sessions.get_mut("idle").unwrap().last_packet = Instant::now() - Duration::from_secs(15);
+        broker.expire();
+        assert_eq!(broker.connection_count(), 0);
+        assert_eq!(disconnect_reason(rx), Some(ReasonCode::KeepAliveTimeout));
+
+        let rx = connect_v5(&mut broker, "slow", 2);
+        broker.set_client_limits("slow", ClientLimits { receive_maximum: 1, ..ClientLimits::default() });
+        broker.subscribe("slow", "a", &options(QoS::AtLeastOnce)).unwrap();
+        for _ in 0..3 {
+            broker.publish(message("a", QoS::AtLeastOnce, false));
+        }
+        assert_eq!(broker.connection_count(), 1);
+        broker.publish(message("a", QoS::AtLeastOnce, false));
+        assert_eq!(broker.connection_count(), 0);
+        assert_eq!(broker.sessions["slow"].queue.len(), 2);
+        assert_eq!(disconnect_reason(rx), Some(ReasonCode::QuotaExceeded));
+    }
+
+    #[test]
+    fn redirects_clients_while_switched_on() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        broker.toggle_redirect();
+        assert_eq!(broker.redirection(), Option::None);
+
+        let mut config = Config::default();
+        config.redirect.server_reference = Some("other:1883".to_string());
+        config.redirect.disconnect_clients = true;
+        let mut broker = Broker::new(&config).unwrap();
+        let rx = connect_v5(&mut broker, "c1", 1);
+        broker.toggle_redirect();
+        assert_eq!(broker.redirection(), Some((ReasonCode::UseAnotherServer, "other:1883")));
+        assert_eq!(broker.connection_count(), 0);
+        let packet = read_packet_with_version(rx.collect().wait().unwrap()[0].clone(), ProtocolVersion::V5).unwrap();
+        assert_eq!(packet.reason_code, Some(ReasonCode::UseAnotherServer));
+        assert_eq!(packet.properties.server_reference, Some("other:1883".to_string()));
+        broker.toggle_redirect();
+        assert_eq!(broker.redirection(), Option::None);
+    }
+}
@@ -0,0 +1,195 @@
+extern crate clap;
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use config::{Config, ConfigError, ListenerConfig, LogLevel};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Command {
+    Serve,
+    CheckConfig,
+}
+
+/* Command-line options of the picomq binary */
+#[derive(Debug, PartialEq, Clone)]
+pub struct Options {
+    pub command: Command,
+    pub config_path: Option<String>,
+    pub listen: Vec<String>,
+    pub log_level: Option<LogLevel>,
+    pub verbosity: u64,
+}
+
+fn validate_address(value: String) -> Result<(), String> {
+    match value.parse::<SocketAddr>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not an IP address with a port", value)),
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("picomq")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Lightweight MQTT broker")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Path to the TOML configuration file")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .value_name("ADDR")
+                .help("Listen on the given address instead of the configured listeners")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_address)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Overrides the configured log level")
+                .takes_value(true)
+                .possible_values(&["error", "warn", "info", "debug", "trace"])
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Increases logging verbosity, may be repeated")
+                .multiple(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("check-config")
+                .long("check-config")
+                .help("Validates the configuration and exits, same as `check-config`"),
+        )
+        .subcommand(SubCommand::with_name("serve").about("Runs the broker (default)"))
+        .subcommand(SubCommand::with_name("check-config").about("Validates the configuration and exits"))
+}
+
+fn options_from_matches(matches: &ArgMatches) -> Options {
+    let command = match matches.subcommand_name() {
+        Some("check-config") => Command::CheckConfig,
+        _ if matches.is_present("check-config") => Command::CheckConfig,
+        _ => Command::Serve,
+    };
+    // Global arguments are propagated to the subcommand matches
+    let matches = match matches.subcommand() {
+        (_, Some(sub)) => sub,
+        _ => matches,
+    };
+    Options {
+        command: command,
+        config_path: matches.value_of("config").map(|s| s.to_string()),
+        listen: matches
+            .values_of("listen")
+            .map(|v| v.map(|s| s.to_string()).collect())
+            .unwrap_or_default(),
+        log_level: matches
+            .value_of("log-level")
+            .map(|s| LogLevel::from_str(s).unwrap()),
+        verbosity: matches.occurrences_of("verbose"),
+    }
+}
+
+pub fn parse_args() -> Options {
+    options_from_matches(&app().get_matches())
+}
+
+impl Options {
+    /// Loads the configuration file (or the defaults) and applies command-line overrides
+    pub fn load_config(&self) -> Result<Config, ConfigError> {
+        let mut config = match self.config_path {
+            Some(ref path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if !self.listen.is_empty() {
+            config.listeners = self.listen
+                .iter()
+                .map(|addr| {
+                    ListenerConfig {
+                        address: addr.clone(),
+                        proxy_protocol: false,
+                    }
+                })
+                .collect();
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+        for _ in 0..self.verbosity {
+            config.log.level = config.log.level.more_verbose();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use cli::*;
+
+    fn parse(args: &[&str]) -> Options {
+        options_from_matches(&app().get_matches_from(args))
+    }
+
+    #[test]
+    fn defaults_to_serve() {
+        let options = parse(&["picomq"]);
+        assert_eq!(options.command, Command::Serve);
+        assert_eq!(options.config_path, None);
+        assert_eq!(options.verbosity, 0);
+        assert_eq!(options.load_config().unwrap(), Config::default());
+    }
+
+    #[test]
+    fn parses_subcommand_with_global_options() {
+        let options = parse(&["picomq", "check-config", "-c", "picomq.toml", "-vv"]);
+        assert_eq!(options.command, Command::CheckConfig);
+        assert_eq!(options.config_path, Some("picomq.toml".to_string()));
+        assert_eq!(options.verbosity, 2);
+
+        let options = parse(&["picomq", "--check-config"]);
+        assert_eq!(options.command, Command::CheckConfig);
+    }
+
+    #[test]
+    fn applies_overrides_to_config() {
+        let options = parse(&[
+            "picomq",
+            "serve",
+            "-l",
+            "127.0.0.1:1883",
+            "-l",
+            "127.0.0.1:1884",
+            "--log-level",
+            "warn",
+            "-v",
+        ]);
+        let config = options.load_config().unwrap();
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[1].address, "127.0.0.1:1884");
+        assert_eq!(config.log.level, LogLevel::Info);
+    }
+
+    #[test]
+    fn rejects_invalid_listen_address() {
+        let result = app().get_matches_from_safe(vec!["picomq", "-l", "localhost"]);
+        assert!(result.is_err());
+    }
+}
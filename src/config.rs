@@ -0,0 +1,503 @@
+extern crate toml;
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use topic;
+
+/* Broker configuration, loaded from a TOML file */
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub log: LogConfig,
+    pub features: FeaturesConfig,
+    pub shutdown: ShutdownConfig,
+    pub redirect: RedirectConfig,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: String,
+    /// Expect a PROXY v1/v2 header before the MQTT stream
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Maximum number of simultaneous connections, 0 means unlimited
+    pub max_connections: usize,
+    pub max_packet_size: u32,
+    /// Longest topic name or filter in bytes, 0 means only the protocol limit applies
+    pub max_topic_length: usize,
+    /// Most levels in a topic name or filter, 0 means unlimited
+    pub max_topic_levels: usize,
+    /// Incoming QoS 2 messages an MQTT 5 client may have unreleased at once
+    pub receive_maximum: u16,
+    /// Topic aliases an MQTT 5 client may set up per connection, 0 disables them
+    pub topic_alias_maximum: u16,
+    /// QoS 1 and 2 messages kept per session for a client that is offline or
+    /// not keeping up, 0 means unlimited
+    pub max_queued_messages: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub password_file: Option<PathBuf>,
+    pub acl_file: Option<PathBuf>,
+    /// What happens to a client publishing where the ACL does not allow it
+    pub denied_publish: DeniedPublish,
+    /// Accept JWTs in the password field instead of a password file
+    pub jwt: Option<JwtConfig>,
+    /// Refuse passwords in CONNECT, clients have to use SCRAM-SHA-256 over AUTH
+    pub require_enhanced_auth: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    /// Shared secret for HS256 tokens
+    pub secret_file: Option<PathBuf>,
+    /// PEM-encoded public key for RS256 tokens
+    pub public_key_file: Option<PathBuf>,
+    pub username_claim: String,
+    /// Seconds of clock skew tolerated when checking `exp` and `nbf`
+    pub leeway: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> JwtConfig {
+        JwtConfig {
+            secret_file: None,
+            public_key_file: None,
+            username_claim: "sub".to_string(),
+            leeway: 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeniedPublish {
+    /// Acknowledge the message and throw it away
+    #[default]
+    Drop,
+    Disconnect,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn more_verbose(self) -> LogLevel {
+        match self {
+            LogLevel::Error => LogLevel::Warn,
+            LogLevel::Warn => LogLevel::Info,
+            LogLevel::Info => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<LogLevel, &'static str> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err("Unknown log level"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+    /// Log full packet contents instead of summaries
+    pub payloads: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesConfig {
+    pub retain: bool,
+    pub wildcard_subscriptions: bool,
+    /// `$share/<group>/<filter>` subscriptions
+    pub shared_subscriptions: bool,
+    pub shared_subscription_strategy: SharedStrategy,
+    /// MQTT 5 Subscription Identifiers, sent back with matching messages
+    pub subscription_identifiers: bool,
+    /// MQTT 5 clients with a username asking for response information get
+    /// `<prefix>/<username>/<client id>` in CONNACK and may use the topics below it
+    /// while connected, empty (the default) disables this
+    pub response_topic_prefix: String,
+}
+
+/// How a shared subscription picks the group member receiving a message
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedStrategy {
+    #[default]
+    RoundRobin,
+    /// Messages of one publisher keep going to the same member while it is connected
+    Sticky,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds to wait for connections to flush and close before exiting
+    pub timeout: u64,
+    /// Send the wills of clients still connected, as if their connections had dropped
+    pub publish_wills: bool,
+}
+
+/// Sending clients to another server, switched on and off with SIGUSR1
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    /// Server Reference given to MQTT 5 clients, such as `other.example.com:1883`
+    pub server_reference: Option<String>,
+    /// Tell clients the server moved for good rather than to use another one for now
+    pub permanent: bool,
+    /// Also disconnect the clients that are connected when redirection starts
+    pub disconnect_clients: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listeners: vec![
+                ListenerConfig {
+                    address: "0.0.0.0:1883".to_string(),
+                    proxy_protocol: false,
+                },
+            ],
+            limits: LimitsConfig::default(),
+            auth: AuthConfig::default(),
+            log: LogConfig::default(),
+            features: FeaturesConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            redirect: RedirectConfig::default(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            max_connections: 0,
+            max_packet_size: MAX_PACKET_SIZE,
+            max_topic_length: 0,
+            max_topic_levels: 0,
+            receive_maximum: 1024,
+            topic_alias_maximum: 16,
+            max_queued_messages: 0,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: LogLevel::Info,
+            format: LogFormat::Human,
+            payloads: false,
+        }
+    }
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> FeaturesConfig {
+        FeaturesConfig {
+            retain: true,
+            wildcard_subscriptions: true,
+            shared_subscriptions: true,
+            shared_subscription_strategy: SharedStrategy::default(),
+            subscription_identifiers: true,
+            response_topic_prefix: String::new(),
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig {
+            timeout: 10,
+            publish_wills: false,
+        }
+    }
+}
+
+/// Largest value the remaining length field can encode
+pub const MAX_PACKET_SIZE: u32 = 268435455;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String),
+    Parse(String),
+    Invalid { key: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "{}", e),
+            ConfigError::Parse(ref e) => write!(f, "{}", e),
+            ConfigError::Invalid {
+                ref key,
+                ref message,
+            } => write!(f, "invalid value for key `{}`: {}", key, message),
+        }
+    }
+}
+
+fn invalid(key: String, message: &str) -> ConfigError {
+    ConfigError::Invalid {
+        key: key,
+        message: message.to_string(),
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| ConfigError::Io(format!("{}: {}", path.display(), e)))?;
+        let config = Config::parse(&contents)
+            .map_err(|e| match e {
+                ConfigError::Parse(e) => ConfigError::Parse(format!("{}: {}", path.display(), e)),
+                e => e,
+            })?;
+        Ok(config)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(contents).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listeners.is_empty() {
+            return Err(invalid("listeners".to_string(), "at least one listener is required"));
+        }
+        let mut addresses = Vec::<SocketAddr>::new();
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let key = format!("listeners[{}].address", i);
+            match listener.address.parse::<SocketAddr>() {
+                Ok(ref addr) if addresses.contains(addr) => {
+                    return Err(invalid(key, "address is used by another listener"))
+                }
+                Ok(addr) => addresses.push(addr),
+                Err(_) => return Err(invalid(key, "expected an IP address with a port")),
+            }
+        }
+
+        if self.limits.max_packet_size < 2 || self.limits.max_packet_size > MAX_PACKET_SIZE {
+            return Err(invalid(
+                "limits.max_packet_size".to_string(),
+                "must be between 2 and 268435455",
+            ));
+        }
+        if self.limits.receive_maximum == 0 {
+            return Err(invalid("limits.receive_maximum".to_string(), "must be at least 1"));
+        }
+
+        let mut files = vec![
+            ("auth.password_file", &self.auth.password_file),
+            ("auth.acl_file", &self.auth.acl_file),
+        ];
+        // Tokens give the broker nothing to run SCRAM against, so nobody could connect
+        if self.auth.require_enhanced_auth && self.auth.jwt.is_some() {
+            return Err(invalid("auth.require_enhanced_auth".to_string(), "cannot be combined with auth.jwt"));
+        }
+        if let Some(ref jwt) = self.auth.jwt {
+            if self.auth.password_file.is_some() {
+                return Err(invalid("auth.jwt".to_string(), "cannot be combined with auth.password_file"));
+            }
+            if jwt.secret_file.is_none() && jwt.public_key_file.is_none() {
+                return Err(invalid("auth.jwt".to_string(), "secret_file or public_key_file is required"));
+            }
+            files.push(("auth.jwt.secret_file", &jwt.secret_file));
+            files.push(("auth.jwt.public_key_file", &jwt.public_key_file));
+        }
+        if self.auth.require_enhanced_auth && self.auth.password_file.is_none() {
+            return Err(invalid("auth.require_enhanced_auth".to_string(), "needs auth.password_file"));
+        }
+        for (key, path) in files {
+            if let Some(ref path) = *path {
+                if !path.is_file() {
+                    return Err(invalid(key.to_string(), "file does not exist"));
+                }
+            }
+        }
+        let prefix = &self.features.response_topic_prefix;
+        if !prefix.is_empty() && topic::validate_name(prefix).is_err() {
+            return Err(invalid("features.response_topic_prefix".to_string(), "must be a topic name"));
+        }
+        if self.redirect.server_reference.as_ref().is_some_and(|r| r.is_empty()) {
+            return Err(invalid("redirect.server_reference".to_string(), "must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use config::*;
+
+    #[test]
+    fn parses_full_config() {
+        let config = Config::parse(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:1883"
+
+            [[listeners]]
+            address = "[::]:1884"
+            proxy_protocol = true
+
+            [limits]
+            max_connections = 100
+
+            [auth]
+            denied_publish = "disconnect"
+
+            [log]
+            level = "debug"
+            format = "json"
+
+            [features]
+            retain = false
+            shared_subscription_strategy = "sticky"
+            subscription_identifiers = false
+            response_topic_prefix = "replies"
+
+            [shutdown]
+            timeout = 3
+
+            [redirect]
+            server_reference = "standby.example.com:1883"
+            "#,
+        ).unwrap();
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[1].proxy_protocol, true);
+        assert_eq!(config.limits.max_connections, 100);
+        assert_eq!(config.limits.max_packet_size, MAX_PACKET_SIZE);
+        assert_eq!(config.auth.denied_publish, DeniedPublish::Disconnect);
+        assert_eq!(config.log.level, LogLevel::Debug);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.features.retain, false);
+        assert_eq!(config.features.wildcard_subscriptions, true);
+        assert_eq!(config.features.shared_subscription_strategy, SharedStrategy::Sticky);
+        assert_eq!(config.features.subscription_identifiers, false);
+        assert_eq!(config.features.response_topic_prefix, "replies");
+        assert_eq!(config.shutdown.timeout, 3);
+        assert_eq!(config.redirect.server_reference, Some("standby.example.com:1883".to_string()));
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn reports_invalid_listener_address() {
+        let err = Config::parse(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:1883"
+
+            [[listeners]]
+            address = "localhost"
+            "#,
+        ).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value for key `listeners[1].address`: expected an IP address with a port"
+        );
+    }
+
+    #[test]
+    fn reports_unknown_and_mistyped_keys() {
+        let err = Config::parse("[limits]\nmax_conections = 1\n").unwrap_err();
+        assert!(err.to_string().contains("max_conections"));
+        let err = Config::parse("[limits]\nmax_connections = \"a\"\n").unwrap_err();
+        assert!(err.to_string().contains("limits.max_connections"));
+        let err = Config::parse("[log]\nformat = \"xml\"\n").unwrap_err();
+        assert!(err.to_string().contains("log.format"));
+        // Nothing is persisted, so a path for it is refused rather than silently ignored
+        let err = Config::parse("[persistence]\npath = \"/var/lib/picomq\"\n").unwrap_err();
+        assert!(err.to_string().contains("persistence"));
+    }
+
+    #[test]
+    fn requires_a_jwt_key() {
+        let err = Config::parse("[auth.jwt]\nusername_claim = \"email\"\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value for key `auth.jwt`: secret_file or public_key_file is required"
+        );
+    }
+
+    #[test]
+    fn rejects_enhanced_auth_with_tokens() {
+        let err = Config::parse(
+            r#"
+            [auth]
+            password_file = "/nonexistent/passwd"
+            require_enhanced_auth = true
+
+            [auth.jwt]
+            secret_file = "/nonexistent/secret"
+            "#,
+        ).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value for key `auth.require_enhanced_auth`: cannot be combined with auth.jwt"
+        );
+    }
+
+    #[test]
+    fn reports_missing_auth_files() {
+        let err = Config::parse("[auth]\npassword_file = \"/nonexistent/passwd\"\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value for key `auth.password_file`: file does not exist"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+extern crate futures;
+extern crate tokio_io;
+
+use std::io::{Error, ErrorKind};
+
+use bytes::Bytes;
+use futures::Future;
+use futures::future::{self, Loop};
+use tokio_io::AsyncRead;
+use tokio_io::io;
+
+/// Reads exactly one MQTT control packet (fixed header included) from the stream.
+/// Fails with `UnexpectedEof` once the peer has closed the connection.
+pub fn read_frame<R>(reader: R, max_size: u32) -> Box<dyn Future<Item = (R, Bytes), Error = Error>>
+where
+    R: AsyncRead + 'static,
+{
+    let header = io::read_exact(reader, [0u8; 1]).and_then(|(reader, first)| {
+        // Remaining length is a variable-length quantity of up to 4 bytes
+        future::loop_fn((reader, vec![first[0]], 0u32), |(reader, mut header, length)| {
+            io::read_exact(reader, [0u8; 1]).and_then(move |(reader, byte)| {
+                let shift = 7 * (header.len() as u32 - 1);
+                let length = length | ((byte[0] as u32 & 127) << shift);
+                header.push(byte[0]);
+                if byte[0] < 128 {
+                    Ok(Loop::Break((reader, header, length)))
+                } else if header.len() == 5 {
+                    Err(Error::new(ErrorKind::InvalidData, "Malformed remaining length"))
+                } else {
+                    Ok(Loop::Continue((reader, header, length)))
+                }
+            })
+        })
+    });
+    let frame = header.and_then(move |(reader, header, length)| {
+        if length as u64 + header.len() as u64 > max_size as u64 {
+            return Err(Error::new(ErrorKind::InvalidData, "Packet exceeds maximum size"));
+        }
+        Ok((reader, header, length))
+    });
+    let frame = frame.and_then(|(reader, mut header, length)| {
+        io::read_exact(reader, vec![0u8; length as usize]).map(move |(reader, body)| {
+            header.extend_from_slice(&body);
+            (reader, Bytes::from(header))
+        })
+    });
+    Box::new(frame)
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, ErrorKind};
+    use bytes::Bytes;
+    use futures::Future;
+    use frame::*;
+
+    #[test]
+    fn reads_consecutive_frames() {
+        // CONNACK followed by PINGRESP
+        let data = Cursor::new(vec![0x20, 0x02, 0x00, 0x00, 0xD0, 0x00]);
+        let (reader, first) = read_frame(data, 1024).wait().unwrap();
+        assert_eq!(first, Bytes::from(vec![0x20, 0x02, 0x00, 0x00]));
+        let (reader, second) = read_frame(reader, 1024).wait().unwrap();
+        assert_eq!(second, Bytes::from(vec![0xD0, 0x00]));
+        let err = read_frame(reader, 1024).wait().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn reads_multi_byte_length() {
+        let mut data = vec![0x30, 0x80, 0x01];
+        data.extend(vec![0u8; 128]);
+        let (_, frame) = read_frame(Cursor::new(data), 1024).wait().unwrap();
+        assert_eq!(frame.len(), 131);
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut data = vec![0x30, 0x80, 0x01];
+        data.extend(vec![0u8; 128]);
+        let err = read_frame(Cursor::new(data), 100).wait().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let data = vec![0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F];
+        let err = read_frame(Cursor::new(data), 100).wait().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
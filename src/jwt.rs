@@ -0,0 +1,233 @@
+extern crate jsonwebtoken;
+extern crate serde_json;
+
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+
+use auth::*;
+use config::JwtConfig;
+use mqtt::*;
+
+/* Bearer tokens in the CONNECT password field, signed with HS256 or RS256 */
+
+fn read_key(path: &Path) -> Result<Vec<u8>, String> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(contents)
+}
+
+/// Claim holding a list of topic filters, missing claims grant nothing
+fn filters(claims: &Map<String, Value>, name: &str) -> Vec<String> {
+    match claims.get(name) {
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+pub struct JwtAuthenticator {
+    secret_file: Option<PathBuf>,
+    public_key_file: Option<PathBuf>,
+    secret: Option<Vec<u8>>,
+    public_key: Option<Vec<u8>>,
+    username_claim: String,
+    leeway: u64,
+}
+
+impl JwtAuthenticator {
+    pub fn load(config: &JwtConfig) -> Result<JwtAuthenticator, String> {
+        let mut authenticator = JwtAuthenticator {
+            secret_file: config.secret_file.clone(),
+            public_key_file: config.public_key_file.clone(),
+            secret: None,
+            public_key: None,
+            username_claim: config.username_claim.clone(),
+            leeway: config.leeway,
+        };
+        authenticator.reload()?;
+        Ok(authenticator)
+    }
+
+    /// Re-reads the key files, keeping the current keys if one is broken
+    pub fn reload(&mut self) -> Result<(), String> {
+        let secret = match self.secret_file {
+            Some(ref path) => {
+                // Editors tend to leave a newline that is not part of the secret
+                let mut secret = read_key(path)?;
+                while secret.last().is_some_and(|b| b.is_ascii_whitespace()) {
+                    secret.pop();
+                }
+                Some(secret)
+            }
+            None => None,
+        };
+        let public_key = match self.public_key_file {
+            Some(ref path) => {
+                let key = read_key(path)?;
+                DecodingKey::from_rsa_pem(&key)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                Some(key)
+            }
+            None => None,
+        };
+        self.secret = secret;
+        self.public_key = public_key;
+        Ok(())
+    }
+
+    /// Verifies the signature, `exp` and `nbf`, returning the claims
+    pub fn verify(&self, token: &str) -> Result<Map<String, Value>, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        let key = match (header.alg, &self.secret, &self.public_key) {
+            (Algorithm::HS256, Some(secret), _) => DecodingKey::from_secret(secret),
+            (Algorithm::RS256, _, Some(key)) => {
+                DecodingKey::from_rsa_pem(key).map_err(|e| e.to_string())?
+            }
+            (alg, _, _) => return Err(format!("no key configured for {:?}", alg)),
+        };
+        let validation = Validation {
+            leeway: self.leeway,
+            ..Validation::new(header.alg)
+        };
+        let claims = jsonwebtoken::decode::<Map<String, Value>>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| e.to_string())?;
+        // `Validation::new` leaves `validate_nbf` off, so `nbf` is only checked here
+        if let Some(nbf) = claims.get("nbf") {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?;
+            match nbf.as_u64() {
+                Some(nbf) if nbf <= now.as_secs() + self.leeway => {}
+                Some(_) => return Err("token is not valid yet".to_string()),
+                None => return Err("invalid `nbf` claim".to_string()),
+            }
+        }
+        Ok(claims)
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(&self, _: &ConnectHeader, payload: &ConnectPayload, peer: &SocketAddr) -> Verdict {
+        let token = match payload.password.as_ref().map(|p| str::from_utf8(p)) {
+            Some(Ok(token)) => token,
+            _ => return Verdict::Reject(ConnAckReturnCode::BadAuth),
+        };
+        let claims = match self.verify(token) {
+            Ok(claims) => claims,
+            Err(e) => {
+                debug!("Invalid token: {}", e);
+                return Verdict::Reject(ConnAckReturnCode::BadAuth);
+            }
+        };
+        let mut identity = ClientIdentity::new(payload, peer);
+        identity.username = claims
+            .get(&self.username_claim)
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string());
+        if claims.contains_key("publish") || claims.contains_key("subscribe") {
+            identity.topics = Some(TopicGrants {
+                publish: filters(&claims, "publish"),
+                subscribe: filters(&claims, "subscribe"),
+            });
+        }
+        Verdict::Accept(identity)
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        JwtAuthenticator::reload(self)
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use base64;
+    use bytes::Bytes;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use jwt::*;
+    use mqtt::reader::read_packet;
+
+    fn authenticator() -> JwtAuthenticator {
+        JwtAuthenticator {
+            secret_file: None,
+            public_key_file: None,
+            secret: Some(b"secret".to_vec()),
+            public_key: None,
+            username_claim: "sub".to_string(),
+            leeway: 0,
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn token(claims: Value) -> String {
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+    }
+
+    fn payload(token: String) -> ConnectPayload {
+        ConnectPayload {
+            client_id: "device".to_string(),
+            password: Some(Bytes::from(token)),
+            ..ConnectPayload::default()
+        }
+    }
+
+    #[test]
+    fn accepts_valid_tokens() {
+        let claims = json!({"sub": "sensor-1", "exp": now() + 60, "publish": ["sensors/1/#"]});
+        let claims = authenticator().verify(&token(claims)).unwrap();
+        assert_eq!(claims["sub"], "sensor-1");
+    }
+
+    #[test]
+    fn rejects_expired_and_premature_tokens() {
+        let expired = token(json!({"sub": "a", "exp": now() - 10}));
+        assert!(authenticator().verify(&expired).is_err());
+        let premature = token(json!({"sub": "a", "exp": now() + 60, "nbf": now() + 30}));
+        assert_eq!(authenticator().verify(&premature).unwrap_err(), "token is not valid yet");
+        let started = token(json!({"sub": "a", "exp": now() + 60, "nbf": now() - 30}));
+        assert!(authenticator().verify(&started).is_ok());
+        let unreadable = token(json!({"sub": "a", "exp": now() + 60, "nbf": "soon"}));
+        assert_eq!(authenticator().verify(&unreadable).unwrap_err(), "invalid `nbf` claim");
+        let no_expiry = token(json!({"sub": "a"}));
+        assert!(authenticator().verify(&no_expiry).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_signatures() {
+        let claims = json!({"sub": "a", "exp": now() + 60});
+        let forged = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"guess")).unwrap();
+        assert!(authenticator().verify(&forged).is_err());
+        let rs256 = format!("{}.e30.c2ln", base64::encode_config(r#"{"alg":"RS256"}"#, base64::URL_SAFE_NO_PAD));
+        assert_eq!(authenticator().verify(&rs256).unwrap_err(), "no key configured for RS256");
+    }
+
+    #[test]
+    fn derives_identity_from_claims() {
+        let claims = json!({"sub": "sensor-1", "exp": now() + 60, "publish": ["sensors/1/#"]});
+        let peer = "127.0.0.1:5000".parse().unwrap();
+        let connect = vec![0x10, 0x0C, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0x02, 0x00, 0x3C, 0x00, 0x00];
+        let header = match read_packet(Bytes::from(connect)).unwrap().var_header {
+            VariableHeader::Connect(h) => h,
+            _ => panic!(),
+        };
+        let identity = authenticator().authenticate(&header, &payload(token(claims)), &peer).wait().unwrap();
+        assert_eq!(identity.username, Some("sensor-1".to_string()));
+        let topics = identity.topics.unwrap();
+        assert!(topics.allows("sensors/1/temp", Action::Publish));
+        assert!(!topics.allows("sensors/2/temp", Action::Publish));
+        assert!(!topics.allows("sensors/1/temp", Action::Subscribe));
+    }
+}
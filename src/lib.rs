@@ -0,0 +1,44 @@
+//! picomq, a lightweight MQTT broker, as a library for running it inside other programs:
+//! build a `Broker`, hand it to a `Server` on an existing reactor and add listeners.
+//! `LocalClient` publishes and subscribes from the program itself.
+
+extern crate futures;
+extern crate futures_cpupool;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate base64;
+extern crate bytes;
+extern crate hmac;
+extern crate jsonwebtoken;
+#[macro_use]
+extern crate log;
+extern crate pbkdf2;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+extern crate toml;
+
+pub mod mqtt;
+mod acl;
+mod alias;
+pub mod auth;
+mod broker;
+pub mod config;
+mod frame;
+mod jwt;
+mod local;
+mod logic;
+pub mod logging;
+pub mod passwd;
+mod proxy;
+mod scram;
+mod server;
+mod topic;
+
+pub use broker::{Broker, BrokerBuilder, ClientLimits, Message, Will, NEVER_EXPIRES};
+pub use local::{LocalClient, Messages};
+pub use server::Server;
@@ -0,0 +1,260 @@
+extern crate futures;
+
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use bytes::Bytes;
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc::{self, UnboundedReceiver};
+
+use auth::ClientIdentity;
+use broker::{Broker, Message};
+use mqtt::*;
+use mqtt::reader::read_packet_with_version;
+use topic;
+
+/* Clients inside the embedding program, routed like any other but without a socket.
+ * They get made-up unspecified addresses, which no TCP peer has and the server refuses
+ * from PROXY headers. An address is not handed out again while its client is alive.
+ */
+
+/// Publishes and subscribes on behalf of the embedding program, the session
+/// ends when it is dropped. Once another client takes the session over, every
+/// call fails.
+pub struct LocalClient {
+    broker: Rc<RefCell<Broker>>,
+    client_id: String,
+    peer: SocketAddr,
+}
+
+/// Messages for the subscriptions of a local client, in the order the broker sent them.
+/// QoS 1 and 2 messages count as acknowledged once taken from the stream.
+pub struct Messages {
+    broker: Rc<RefCell<Broker>>,
+    client_id: String,
+    peer: SocketAddr,
+    rx: UnboundedReceiver<Bytes>,
+}
+
+impl LocalClient {
+    /// Starts a clean session for `client_id`, taking it over from any other client
+    pub fn connect(broker: &Rc<RefCell<Broker>>, client_id: &str) -> Result<(LocalClient, Messages), &'static str> {
+        let (tx, rx) = mpsc::unbounded();
+        let peer = {
+            let mut broker = broker.borrow_mut();
+            let peer = broker.reserve_local_peer().ok_or("Too many local clients")?;
+            broker.open(peer, tx);
+            let identity = ClientIdentity {
+                client_id: client_id.to_string(),
+                username: None,
+                peer: peer,
+                topics: None,
+            };
+            broker.attach(&identity, ProtocolVersion::V5, true, 0, None);
+            peer
+        };
+        let client = LocalClient {
+            broker: broker.clone(),
+            client_id: client_id.to_string(),
+            peer: peer,
+        };
+        let messages = Messages {
+            broker: broker.clone(),
+            client_id: client_id.to_string(),
+            peer: peer,
+            rx: rx,
+        };
+        Ok((client, messages))
+    }
+
+    /// Routes the message as sent by this client, returning the number of sessions it reached
+    pub fn publish(&self, message: Message) -> Result<usize, &'static str> {
+        topic::validate_name(&message.topic)?;
+        if message.qos == QoS::Reserved {
+            return Err("Invalid QoS level");
+        }
+        let mut broker = self.broker.borrow_mut();
+        if !broker.is_attached(&self.peer, &self.client_id) {
+            return Err("Session taken over by another client");
+        }
+        broker.check_topic(&message.topic)?;
+        Ok(broker.publish(Message {
+            sender: Some(self.client_id.clone()),
+            expiry: None,
+            ..message
+        }))
+    }
+
+    /// Subscribes and sends the matching retained messages, returning the granted QoS
+    pub fn subscribe(&self, filter: &str, qos: QoS) -> Result<QoS, ReasonCode> {
+        let mut broker = self.broker.borrow_mut();
+        if !broker.is_attached(&self.peer, &self.client_id) {
+            return Err(ReasonCode::SessionTakenOver);
+        }
+        if topic::validate_filter(filter).is_err() || broker.check_topic(filter).is_err() {
+            return Err(ReasonCode::TopicFilterInvalid);
+        }
+        let granted = broker.subscribe(&self.client_id, filter, &SubscriptionOptions::new(qos))?;
+        broker.send_retained(&self.client_id, filter);
+        Ok(granted)
+    }
+
+    /// Returns whether there was such a subscription
+    pub fn unsubscribe(&self, filter: &str) -> Result<bool, ReasonCode> {
+        let mut broker = self.broker.borrow_mut();
+        if !broker.is_attached(&self.peer, &self.client_id) {
+            return Err(ReasonCode::SessionTakenOver);
+        }
+        Ok(broker.unsubscribe(&self.client_id, filter))
+    }
+}
+
+impl Drop for LocalClient {
+    fn drop(&mut self) {
+        let mut broker = self.broker.borrow_mut();
+        // The session belongs to whoever took it over
+        if broker.is_attached(&self.peer, &self.client_id) {
+            broker.disconnect(&self.peer);
+        }
+        broker.release_local_peer(&self.peer);
+    }
+}
+
+impl Stream for Messages {
+    type Item = Message;
+    type Error = ();
+
+    /// Ends once the session is taken over, the broker shuts down or the client is dropped
+    fn poll(&mut self) -> Poll<Option<Message>, ()> {
+        loop {
+            let bytes = match self.rx.poll()? {
+                Async::Ready(Some(bytes)) => bytes,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            let packet = match read_packet_with_version(bytes, ProtocolVersion::V5) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    error!("Unreadable packet for local client {:?}: {}", self.client_id, e);
+                    continue;
+                }
+            };
+            let header = match packet.var_header {
+                VariableHeader::Publish(header) => header,
+                _ if packet.header.packet_type == PacketType::Disconnect => return Ok(Async::Ready(None)),
+                _ => continue,
+            };
+            // Packet ids of a session taken over are the new owner's to acknowledge
+            let mut broker = self.broker.borrow_mut();
+            if header.packet_id != 0 && broker.is_attached(&self.peer, &self.client_id) {
+                broker.acknowledge(&self.client_id, header.packet_id);
+            }
+            return Ok(Async::Ready(Some(Message {
+                topic: header.topic_name,
+                payload: packet.payload,
+                qos: packet.header.qos,
+                retain: packet.header.retain,
+                properties: packet.properties,
+                sender: None,
+                expiry: None,
+            })));
+        }
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use broker::*;
+    use config::Config;
+    use futures::{Future, Stream};
+    use local::*;
+
+    #[test]
+    fn routes_between_local_clients() {
+        let broker = Rc::new(RefCell::new(Broker::new(&Config::default()).unwrap()));
+        let (publisher, _) = LocalClient::connect(&broker, "pub").unwrap();
+        let retained = Message::new("a/1", Bytes::from(&b"retained"[..]), QoS::AtLeastOnce, true);
+        assert_eq!(publisher.publish(retained), Ok(0));
+        let (subscriber, messages) = LocalClient::connect(&broker, "sub").unwrap();
+        assert_eq!(subscriber.subscribe("a/+", QoS::AtLeastOnce), Ok(QoS::AtLeastOnce));
+        assert_eq!(subscriber.subscribe("a/#/b", QoS::AtLeastOnce), Err(ReasonCode::TopicFilterInvalid));
+        let message = Message::new("a/2", Bytes::from(&b"data"[..]), QoS::ExactlyOnce, false);
+        assert_eq!(publisher.publish(message), Ok(1));
+        assert!(publisher.publish(Message::new("a/+", Bytes::new(), QoS::AtMostOnce, false)).is_err());
+        drop(subscriber);
+
+        let received: Vec<(String, QoS, bool)> = messages
+            .map(|m| (m.topic, m.qos, m.retain))
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(received, vec![
+            ("a/1".to_string(), QoS::AtLeastOnce, true),
+            ("a/2".to_string(), QoS::AtLeastOnce, false),
+        ]);
+    }
+
+    #[test]
+    fn unsubscribes() {
+        let broker = Rc::new(RefCell::new(Broker::new(&Config::default()).unwrap()));
+        let (client, _) = LocalClient::connect(&broker, "c").unwrap();
+        assert_eq!(client.subscribe("a", QoS::AtMostOnce), Ok(QoS::AtMostOnce));
+        assert_eq!(client.unsubscribe("a"), Ok(true));
+        assert_eq!(client.unsubscribe("a"), Ok(false));
+        assert_eq!(client.publish(Message::new("a", Bytes::new(), QoS::AtMostOnce, false)), Ok(0));
+    }
+
+    #[test]
+    fn fails_once_taken_over() {
+        let broker = Rc::new(RefCell::new(Broker::new(&Config::default()).unwrap()));
+        let (stale, stale_messages) = LocalClient::connect(&broker, "c").unwrap();
+        let (owner, messages) = LocalClient::connect(&broker, "c").unwrap();
+        let message = Message::new("a", Bytes::new(), QoS::AtLeastOnce, false);
+        assert!(stale.publish(message.clone()).is_err());
+        assert_eq!(stale.subscribe("a", QoS::AtLeastOnce), Err(ReasonCode::SessionTakenOver));
+        assert_eq!(stale.unsubscribe("a"), Err(ReasonCode::SessionTakenOver));
+        assert_eq!(stale_messages.collect().wait().unwrap().len(), 0);
+
+        // The address of the stale client stays taken until it is dropped
+        let (publisher, _) = LocalClient::connect(&broker, "pub").unwrap();
+        assert!(publisher.peer != stale.peer);
+        assert_eq!(owner.subscribe("a", QoS::AtLeastOnce), Ok(QoS::AtLeastOnce));
+        drop(stale);
+        assert_eq!(publisher.publish(message), Ok(1));
+        drop(owner);
+        assert_eq!(messages.collect().wait().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn ends_when_the_broker_shuts_down() {
+        let broker = Rc::new(RefCell::new(Broker::new(&Config::default()).unwrap()));
+        let (client, messages) = LocalClient::connect(&broker, "c").unwrap();
+        assert_eq!(client.subscribe("a", QoS::AtMostOnce), Ok(QoS::AtMostOnce));
+        assert_eq!(client.publish(Message::new("a", Bytes::new(), QoS::AtMostOnce, false)), Ok(1));
+        broker.borrow_mut().shutdown(false);
+        assert_eq!(messages.collect().wait().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn acknowledges_qos_2_messages_when_taken() {
+        let broker = Rc::new(RefCell::new(Broker::new(&Config::default()).unwrap()));
+        let (publisher, _) = LocalClient::connect(&broker, "pub").unwrap();
+        let (subscriber, messages) = LocalClient::connect(&broker, "sub").unwrap();
+        broker.borrow_mut().set_client_limits("sub", ClientLimits { receive_maximum: 1, ..ClientLimits::default() });
+        assert_eq!(subscriber.subscribe("a", QoS::ExactlyOnce), Ok(QoS::ExactlyOnce));
+        for payload in &["1", "2", "3"] {
+            let message = Message::new("a", Bytes::from(payload.as_bytes()), QoS::ExactlyOnce, false);
+            assert_eq!(publisher.publish(message), Ok(1));
+        }
+
+        // Only one is in flight at a time, so the rest arrive as each is taken
+        let received: Vec<(Bytes, QoS)> = messages.take(3).map(|m| (m.payload, m.qos)).collect().wait().unwrap();
+        assert_eq!(received, vec![
+            (Bytes::from(&b"1"[..]), QoS::ExactlyOnce),
+            (Bytes::from(&b"2"[..]), QoS::ExactlyOnce),
+            (Bytes::from(&b"3"[..]), QoS::ExactlyOnce),
+        ]);
+    }
+}
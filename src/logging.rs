@@ -0,0 +1,205 @@
+extern crate log;
+extern crate serde_json;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use config::{LogConfig, LogFormat, LogLevel};
+
+/* Per-connection fields attached to every record logged while handling it */
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConnectionContext {
+    pub peer: SocketAddr,
+    pub client_id: Option<String>,
+}
+
+impl ConnectionContext {
+    pub fn new(peer: SocketAddr) -> ConnectionContext {
+        ConnectionContext {
+            peer: peer,
+            client_id: None,
+        }
+    }
+}
+
+// The reactor is single-threaded, so the connection being handled is a per-thread value
+thread_local!(static CONTEXT: RefCell<Option<ConnectionContext>> = const { RefCell::new(None) });
+
+/// Runs `f` with `context` attached to everything it logs
+pub fn with_context<F, R>(context: &ConnectionContext, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let previous = CONTEXT.with(|c| c.replace(Some(context.clone())));
+    let result = f();
+    CONTEXT.with(|c| *c.borrow_mut() = previous);
+    result
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+fn timestamp() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9,
+        Err(_) => 0.0,
+    }
+}
+
+fn format_human(record: &Record, context: &Option<ConnectionContext>) -> String {
+    let prefix = match *context {
+        Some(ConnectionContext {
+            ref peer,
+            client_id: Some(ref id),
+        }) => format!("[{} {}] ", peer, id),
+        Some(ConnectionContext { ref peer, .. }) => format!("[{}] ", peer),
+        None => String::new(),
+    };
+    format!(
+        "{:.3} {:<5} {}{}",
+        timestamp(),
+        record.level(),
+        prefix,
+        record.args()
+    )
+}
+
+fn format_json(record: &Record, context: &Option<ConnectionContext>) -> String {
+    let mut line = serde_json::Map::new();
+    line.insert("ts".to_string(), json!(timestamp()));
+    line.insert("level".to_string(), json!(record.level().to_string()));
+    line.insert("target".to_string(), json!(record.target()));
+    line.insert("msg".to_string(), json!(record.args().to_string()));
+    if let Some(ref context) = *context {
+        line.insert("peer".to_string(), json!(context.peer.to_string()));
+        if let Some(ref id) = context.client_id {
+            line.insert("client_id".to_string(), json!(id));
+        }
+    }
+    serde_json::Value::Object(line).to_string()
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Dependencies (the reactor in particular) are too chatty below INFO
+        metadata.level() <= self.level &&
+            (metadata.level() <= Level::Info || metadata.target().starts_with("picomq"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = CONTEXT.with(|c| match self.format {
+            LogFormat::Human => format_human(record, &c.borrow()),
+            LogFormat::Json => format_json(record, &c.borrow()),
+        });
+        let stderr = io::stderr();
+        let _ = writeln!(stderr.lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+pub fn init(config: &LogConfig) {
+    let level = level_filter(config.level);
+    let logger = Logger {
+        level: level,
+        format: config.format,
+    };
+    log::set_boxed_logger(Box::new(logger)).expect("Logger is already initialized");
+    log::set_max_level(level);
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use logging::*;
+
+    fn current() -> Option<ConnectionContext> {
+        CONTEXT.with(|c| c.borrow().clone())
+    }
+
+    fn context(client_id: Option<&str>) -> Option<ConnectionContext> {
+        Some(ConnectionContext {
+            peer: "127.0.0.1:50000".parse().unwrap(),
+            client_id: client_id.map(|id| id.to_string()),
+        })
+    }
+
+    #[test]
+    fn formats_human_lines_with_the_connection() {
+        let line = format_human(
+            &Record::builder().args(format_args!("Connection opened")).level(Level::Info).build(),
+            &context(None),
+        );
+        assert!(line.ends_with(" INFO  [127.0.0.1:50000] Connection opened"));
+        let line = format_human(
+            &Record::builder().args(format_args!("Bad packet")).level(Level::Warn).build(),
+            &context(Some("sensor")),
+        );
+        assert!(line.ends_with(" WARN  [127.0.0.1:50000 sensor] Bad packet"));
+        let line = format_human(
+            &Record::builder().args(format_args!("Listening")).level(Level::Error).build(),
+            &None,
+        );
+        assert!(line.ends_with(" ERROR Listening"));
+    }
+
+    #[test]
+    fn escapes_json_fields() {
+        let line = format_json(
+            &Record::builder()
+                .args(format_args!("Rejected \"a/b\"\nnext"))
+                .level(Level::Warn)
+                .target("picomq::broker")
+                .build(),
+            &context(Some("id \"1\"")),
+        );
+        assert!(!line.contains('\n'));
+        assert!(line.contains(r#""msg":"Rejected \"a/b\"\nnext""#));
+        let fields: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(fields["level"], "WARN");
+        assert_eq!(fields["target"], "picomq::broker");
+        assert_eq!(fields["msg"], "Rejected \"a/b\"\nnext");
+        assert_eq!(fields["peer"], "127.0.0.1:50000");
+        assert_eq!(fields["client_id"], "id \"1\"");
+
+        let line = format_json(&Record::builder().args(format_args!("Started")).build(), &None);
+        let fields: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(fields.get("peer").is_none());
+        assert!(fields.get("client_id").is_none());
+    }
+
+    #[test]
+    fn nests_and_restores_contexts() {
+        let outer = context(None).unwrap();
+        let inner = context(Some("inner")).unwrap();
+        assert_eq!(current(), None);
+        let result = with_context(&outer, || {
+            assert_eq!(current(), Some(outer.clone()));
+            with_context(&inner, || assert_eq!(current(), Some(inner.clone())));
+            assert_eq!(current(), Some(outer.clone()));
+            42
+        });
+        assert_eq!(result, 42);
+        assert_eq!(current(), None);
+    }
+}
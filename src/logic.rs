@@ -1,9 +1,758 @@
 extern crate bytes;
 
+use std::collections::HashSet;
+
 use bytes::Bytes;
+use rand::Rng;
+
+use alias::InboundAliases;
+use auth::{Action, ClientIdentity, PasswordCheck, Verdict};
+use broker::{Broker, ClientLimits, Message, Will, NEVER_EXPIRES};
+use config::DeniedPublish;
+use logging::{with_context, ConnectionContext};
 use mqtt::*;
-use mqtt::reader::*;
+use mqtt::writer;
+use scram::{self, ScramExchange};
+use topic;
+
+/// MQTT 3.1 limits client ids, later versions only suggest servers accept at least this many
+const MQTT_31_MAX_CLIENT_ID_LENGTH: usize = 23;
+
+/* Per-connection protocol state */
+pub struct Connection {
+    pub context: ConnectionContext,
+    /// Set once CONNECT has been accepted
+    pub identity: Option<ClientIdentity>,
+    /// Taken from CONNECT, MQTT 3.1.1 until then
+    pub version: ProtocolVersion,
+    /// Whether an MQTT 5 client wants reason strings on packets other than CONNACK and DISCONNECT
+    problem_information: bool,
+    /// Seconds the session is kept after this connection, from CONNECT
+    session_expiry_interval: u32,
+    /// Incoming QoS 2 packet ids not released yet, so duplicates are not routed twice
+    awaiting_release: HashSet<u16>,
+    /// Topic aliases the client set up in its PUBLISH packets
+    aliases: InboundAliases,
+    /// Set when CONNECT used enhanced authentication, re-authentication has to use it too
+    authentication_method: Option<String>,
+    /// Enhanced authentication under way
+    authentication: Option<Authentication>,
+    /// CONNECT waiting for the password check the authenticator asked for
+    checking: Option<(ConnectRequest, ClientIdentity)>,
+}
+
+impl Connection {
+    pub fn new(context: ConnectionContext) -> Connection {
+        Connection {
+            context: context,
+            identity: None,
+            version: ProtocolVersion::V311,
+            problem_information: true,
+            session_expiry_interval: 0,
+            awaiting_release: HashSet::new(),
+            aliases: InboundAliases::new(0),
+            authentication_method: None,
+            authentication: None,
+            checking: None,
+        }
+    }
+
+    fn reason_string(&self, reason: &str) -> Properties {
+        if !self.problem_information {
+            return Properties::default();
+        }
+        reason_string(reason)
+    }
+}
+
+/// A CONNECT that passed the checks, waiting to be authenticated
+struct ConnectRequest {
+    header: ConnectHeader,
+    payload: ConnectPayload,
+    properties: Properties,
+    /// Client id picked for an MQTT 5 client that sent none
+    assigned_client_id: Option<String>,
+}
+
+/// SCRAM exchange of a CONNECT, which waits for it, or of a re-authentication
+struct Authentication {
+    exchange: ScramExchange,
+    connect: Option<ConnectRequest>,
+}
+
+/// What the connection should do after a packet has been handled
+pub enum Response {
+    None,
+    Reply(Bytes),
+    /// Close the connection after sending the packet, if any
+    Close(Option<Bytes>),
+    /// Run the check off the reactor thread and hand the result to `checked`,
+    /// reading nothing else from the client meanwhile
+    Check(PasswordCheck),
+}
+
+fn reason_string(reason: &str) -> Properties {
+    Properties {
+        reason_string: Some(reason.to_string()),
+        ..Properties::default()
+    }
+}
+
+/// DISCONNECT telling an MQTT 5 client why the server closes its connection
+pub fn disconnect(connection: &Connection, code: ReasonCode, reason: &str) -> Option<Bytes> {
+    match (connection.version, &connection.identity) {
+        (ProtocolVersion::V5, &Some(_)) => Some(writer::v5::disconnect(code, &reason_string(reason))),
+        _ => None,
+    }
+}
+
+/// Rejects a CONNECT with the closest code the client's version has
+fn refuse(version: ProtocolVersion, code: ReasonCode, reason: &str) -> Response {
+    let reply = match version {
+        ProtocolVersion::V5 => writer::v5::connack(false, code, &reason_string(reason)),
+        _ => writer::connack(false, code.to_connack_return_code()),
+    };
+    Response::Close(Some(reply))
+}
+
+/// Properties of an AUTH or CONNACK packet carrying the next SCRAM message
+fn authentication_properties(data: Vec<u8>) -> Properties {
+    Properties {
+        authentication_method: Some(scram::MECHANISM.to_string()),
+        authentication_data: Some(Bytes::from(data)),
+        ..Properties::default()
+    }
+}
+
+fn packet_id(packet: &MqttPacket) -> Result<u16, &'static str> {
+    match packet.var_header {
+        VariableHeader::WithPacketId(id) => Ok(id),
+        _ => Err("Expected a packet identifier"),
+    }
+}
+
+fn connect(
+    packet: MqttPacket,
+    connection: &mut Connection,
+    broker: &mut Broker,
+) -> Result<Response, &'static str> {
+    let header = match packet.var_header {
+        VariableHeader::Connect(ref h) => h.clone(),
+        _ => return Err("Found non-CONNECT varheader in CONNECT packet type"),
+    };
+    if header.protocol_name != "MQTT" && header.protocol_name != "MQIsdp" {
+        return Err("Unknown protocol name");
+    }
+    let version = match ProtocolVersion::from_connect(&header.protocol_name, header.protocol_level) {
+        Some(version) => version,
+        None => {
+            let reason = "Unsupported protocol level";
+            return Ok(refuse(ProtocolVersion::V311, ReasonCode::UnsupportedProtocolVersion, reason));
+        }
+    };
+    connection.version = version;
+    if let Err(reason) = header.check_flags() {
+        // Earlier versions have no return code for it, the connection is just closed
+        return Ok(match version {
+            ProtocolVersion::V5 => refuse(version, ReasonCode::MalformedPacket, reason),
+            _ => Response::Close(None),
+        });
+    }
+    let properties = packet.properties.clone();
+    connection.problem_information = properties.request_problem_information != Some(0);
+    if let Some((code, reference)) = broker.redirection() {
+        info!("Redirected client to {}", reference);
+        let reason = "Server is redirecting clients";
+        return Ok(match version {
+            ProtocolVersion::V5 => {
+                let properties = Properties {
+                    server_reference: Some(reference.to_string()),
+                    ..reason_string(reason)
+                };
+                Response::Close(Some(writer::v5::connack(false, code, &properties)))
+            }
+            _ => refuse(version, code, reason),
+        });
+    }
+    let mut payload = packet.get_connect_payload()?;
+    if version == ProtocolVersion::V31 && payload.client_id.chars().count() > MQTT_31_MAX_CLIENT_ID_LENGTH {
+        return Ok(refuse(version, ReasonCode::ClientIdentifierNotValid, "Client identifier is too long"));
+    }
+    let mut assigned_client_id = None;
+    if payload.client_id.is_empty() {
+        // MQTT 3.1 always needs an id, in 3.1.1 only clients that keep no session
+        // may leave picking it to the server
+        let required = match version {
+            ProtocolVersion::V31 => true,
+            ProtocolVersion::V311 => !header.clean_session(),
+            ProtocolVersion::V5 => false,
+        };
+        if required {
+            let reason = "Client identifier is required";
+            return Ok(refuse(version, ReasonCode::ClientIdentifierNotValid, reason));
+        }
+        payload.client_id = format!("picomq-{:016x}", rand::thread_rng().gen::<u64>());
+        assigned_client_id = Some(payload.client_id.clone());
+    }
+    let request = ConnectRequest {
+        header: header,
+        payload: payload,
+        properties: properties,
+        assigned_client_id: assigned_client_id,
+    };
+
+    if let Some(method) = request.properties.authentication_method.clone() {
+        if method != scram::MECHANISM || !broker.supports_scram() {
+            let reason = "Unsupported authentication method";
+            return Ok(refuse(version, ReasonCode::BadAuthenticationMethod, reason));
+        }
+        let data = request.properties.authentication_data.clone().unwrap_or_default();
+        return Ok(match ScramExchange::start(&data, |username| broker.scram_credentials(username)) {
+            Ok((exchange, server_first)) => {
+                connection.authentication_method = Some(method);
+                connection.authentication = Some(Authentication {
+                    exchange: exchange,
+                    connect: Some(request),
+                });
+                let properties = authentication_properties(server_first);
+                Response::Reply(writer::v5::auth(ReasonCode::ContinueAuthentication, &properties))
+            }
+            Err(reason) => {
+                warn!("Rejected client {:?}: {}", request.payload.client_id, reason);
+                refuse(version, ReasonCode::BadUserNameOrPassword, "Authentication failed")
+            }
+        });
+    }
+    if broker.requires_enhanced_auth() {
+        warn!("Rejected client {:?}: enhanced authentication is required", request.payload.client_id);
+        let reason = "Enhanced authentication is required";
+        return Ok(refuse(version, ReasonCode::BadAuthenticationMethod, reason));
+    }
+    let peer = connection.context.peer;
+    match broker.authenticate(&request.header, &request.payload, &peer) {
+        Verdict::Accept(identity) => accept(request, identity, Properties::default(), connection, broker),
+        Verdict::Reject(code) => Ok(reject(request, version, code)),
+        Verdict::Check(identity, check) => {
+            connection.checking = Some((request, identity));
+            Ok(Response::Check(check))
+        }
+    }
+}
+
+fn reject(request: ConnectRequest, version: ProtocolVersion, code: ConnAckReturnCode) -> Response {
+    warn!(
+        "Rejected client {:?} with username {:?}: {:?}",
+        request.payload.client_id,
+        request.payload.username,
+        code
+    );
+    refuse(version, ReasonCode::from_connack_return_code(code), "Authentication failed")
+}
+
+/// Completes the CONNECT whose password check has run
+pub fn checked(passed: bool, connection: &mut Connection, broker: &mut Broker) -> Result<Response, &'static str> {
+    let (request, identity) = connection.checking.take().ok_or("No CONNECT waits for a password check")?;
+    match passed {
+        true => accept(request, identity, Properties::default(), connection, broker),
+        false => Ok(reject(request, connection.version, ConnAckReturnCode::BadAuth)),
+    }
+}
+
+/// Completes an authenticated CONNECT, `authentication` goes into the MQTT 5 CONNACK
+fn accept(
+    request: ConnectRequest,
+    identity: ClientIdentity,
+    authentication: Properties,
+    connection: &mut Connection,
+    broker: &mut Broker,
+) -> Result<Response, &'static str> {
+    let ConnectRequest { header, payload, properties, assigned_client_id } = request;
+    let version = connection.version;
+    let peer = connection.context.peer;
+    let will = match (payload.will_topic, payload.will_message) {
+        (Some(topic), Some(message)) => Some(Will {
+            message: Message {
+                topic: topic,
+                payload: message,
+                qos: header.will_qos(),
+                retain: header.will_retain(),
+                properties: payload.will_properties.forwarded(),
+                sender: Some(identity.client_id.clone()),
+                expiry: None,
+            },
+            delay_interval: payload.will_properties.will_delay_interval.unwrap_or(0),
+        }),
+        _ => None,
+    };
+    if let Some(Will { message: ref will, .. }) = will {
+        broker.check_topic(&will.topic)?;
+        if !broker.authorize(&identity, &will.topic, &will.qos, Action::Publish) {
+            warn!("Rejected client {:?}: will topic {:?} is not allowed", identity.client_id, will.topic);
+            return Ok(refuse(version, ReasonCode::NotAuthorized, "Will topic is not allowed"));
+        }
+    }
+
+    // Sessions of earlier versions are either gone with the connection or kept forever
+    let clean_start = header.clean_session();
+    let expiry_interval = match version {
+        ProtocolVersion::V5 => properties.session_expiry_interval.unwrap_or(0),
+        _ if clean_start => 0,
+        _ => NEVER_EXPIRES,
+    };
+    connection.session_expiry_interval = expiry_interval;
+    let session_present = broker.attach(&identity, version, clean_start, expiry_interval, will);
+    broker.set_keep_alive(&identity.client_id, header.keep_alive);
+    if version == ProtocolVersion::V5 {
+        connection.aliases = InboundAliases::new(broker.topic_alias_maximum());
+        let defaults = ClientLimits::default();
+        let limits = ClientLimits {
+            receive_maximum: properties.receive_maximum.unwrap_or(defaults.receive_maximum),
+            maximum_packet_size: properties.maximum_packet_size.unwrap_or(defaults.maximum_packet_size),
+            topic_alias_maximum: properties.topic_alias_maximum.unwrap_or(defaults.topic_alias_maximum),
+        };
+        broker.set_client_limits(&identity.client_id, limits);
+    }
+    connection.context.client_id = Some(identity.client_id.clone());
+    with_context(&connection.context, || info!("Client connected"));
+    let response_information = match properties.request_response_information {
+        Some(1) => broker.grant_response_topic(&identity),
+        _ => None,
+    };
+    let reply = match version {
+        ProtocolVersion::V5 => {
+            let mut properties = broker.connack_properties();
+            properties.assigned_client_identifier = assigned_client_id;
+            properties.response_information = response_information;
+            properties.authentication_method = authentication.authentication_method;
+            properties.authentication_data = authentication.authentication_data;
+            writer::v5::connack(session_present, ReasonCode::Success, &properties)
+        }
+        // The session present flag is a reserved bit in MQTT 3.1
+        ProtocolVersion::V31 => writer::connack(false, ConnAckReturnCode::Accepted),
+        ProtocolVersion::V311 => writer::connack(session_present, ConnAckReturnCode::Accepted),
+    };
+    broker.send(&peer, reply);
+    broker.resume(&identity.client_id);
+    connection.identity = Some(identity);
+    Ok(Response::None)
+}
+
+/// AUTH packets of a SCRAM exchange, started by CONNECT or by the client
+/// re-authenticating an established connection
+fn auth(
+    packet: MqttPacket,
+    connection: &mut Connection,
+    broker: &mut Broker,
+) -> Result<Response, &'static str> {
+    if connection.authentication_method.is_none() {
+        return Err("AUTH without enhanced authentication in CONNECT");
+    }
+    if packet.properties.authentication_method != connection.authentication_method {
+        return Err("AUTH with a different authentication method than CONNECT");
+    }
+    let data = packet.properties.authentication_data.clone().unwrap_or_default();
+    match packet.reason_code {
+        Some(ReasonCode::ReAuthenticate) if connection.identity.is_some() && connection.authentication.is_none() => {
+            Ok(match ScramExchange::start(&data, |username| broker.scram_credentials(username)) {
+                Ok((exchange, server_first)) => {
+                    connection.authentication = Some(Authentication {
+                        exchange: exchange,
+                        connect: None,
+                    });
+                    let properties = authentication_properties(server_first);
+                    Response::Reply(writer::v5::auth(ReasonCode::ContinueAuthentication, &properties))
+                }
+                Err(reason) => {
+                    warn!("Re-authentication failed: {}", reason);
+                    Response::Close(disconnect(connection, ReasonCode::NotAuthorized, "Authentication failed"))
+                }
+            })
+        }
+        Some(ReasonCode::ContinueAuthentication) => {
+            let authentication = match connection.authentication.take() {
+                Some(authentication) => authentication,
+                None => return Err("AUTH without an authentication exchange in progress"),
+            };
+            let username = authentication.exchange.username.clone();
+            let result = authentication.exchange.finish(&data);
+            match (result, authentication.connect) {
+                (Ok(server_final), Some(request)) => {
+                    let mut identity = ClientIdentity::new(&request.payload, &connection.context.peer);
+                    identity.username = Some(username);
+                    accept(request, identity, authentication_properties(server_final), connection, broker)
+                }
+                (Err(reason), Some(request)) => {
+                    warn!("Rejected client {:?} with username {:?}: {}", request.payload.client_id, username, reason);
+                    Ok(refuse(connection.version, ReasonCode::BadUserNameOrPassword, "Authentication failed"))
+                }
+                // Re-authentication may refresh the credentials, not switch to another user
+                (Ok(_), None) if connection.identity.as_ref().unwrap().username != Some(username) => {
+                    warn!("Re-authentication as a different user");
+                    let reason = "Re-authentication as a different user";
+                    Ok(Response::Close(disconnect(connection, ReasonCode::NotAuthorized, reason)))
+                }
+                (Ok(server_final), None) => {
+                    with_context(&connection.context, || info!("Client re-authenticated"));
+                    let properties = authentication_properties(server_final);
+                    Ok(Response::Reply(writer::v5::auth(ReasonCode::Success, &properties)))
+                }
+                (Err(reason), None) => {
+                    warn!("Re-authentication failed: {}", reason);
+                    Ok(Response::Close(disconnect(connection, ReasonCode::NotAuthorized, "Authentication failed")))
+                }
+            }
+        }
+        _ => Err("Unexpected AUTH packet"),
+    }
+}
+
+/// PUBACK or PUBREC for an incoming PUBLISH, MQTT 3.1.1 ones ignore the reason
+fn publish_ack(
+    connection: &Connection,
+    qos: &QoS,
+    packet_id: u16,
+    code: ReasonCode,
+    properties: Properties,
+) -> Response {
+    let packet_type = match *qos {
+        QoS::AtLeastOnce => PacketType::PubAck,
+        QoS::ExactlyOnce => PacketType::PubRec,
+        _ => return Response::None,
+    };
+    Response::Reply(match connection.version {
+        ProtocolVersion::V5 => writer::v5::ack(&packet_type, packet_id, code, &properties),
+        _ if packet_type == PacketType::PubAck => writer::puback(packet_id),
+        _ => writer::pubrec(packet_id),
+    })
+}
+
+fn publish(
+    packet: MqttPacket,
+    connection: &mut Connection,
+    broker: &mut Broker,
+) -> Result<Response, &'static str> {
+    let mut header = match packet.var_header {
+        VariableHeader::Publish(ref h) => h.clone(),
+        _ => return Err("Found non-PUBLISH varheader in PUBLISH packet type"),
+    };
+    if !packet.properties.subscription_identifiers.is_empty() {
+        return Err("Subscription Identifier in a PUBLISH from a client");
+    }
+    header.topic_name = match connection.aliases.resolve(header.topic_name, packet.properties.topic_alias) {
+        Ok(topic) => topic,
+        Err(reason) => {
+            warn!("Invalid topic alias {:?}: {}", packet.properties.topic_alias, reason);
+            return Ok(Response::Close(disconnect(connection, ReasonCode::TopicAliasInvalid, reason)));
+        }
+    };
+    broker.check_topic(&header.topic_name)?;
+    let qos = packet.header.qos.clone();
+    if qos == QoS::Reserved {
+        return Err("Invalid QoS level");
+    }
+    let id = header.packet_id;
+    if qos == QoS::ExactlyOnce && connection.awaiting_release.contains(&id) {
+        // Retransmission of a message that was already routed
+        return Ok(publish_ack(connection, &qos, id, ReasonCode::Success, Properties::default()));
+    }
+    // QoS 1 messages are acknowledged right away, QoS 2 ones count until released
+    let receive_maximum = broker.receive_maximum() as usize;
+    if connection.version == ProtocolVersion::V5
+        && qos == QoS::ExactlyOnce
+        && connection.awaiting_release.len() >= receive_maximum
+    {
+        let reason = "More QoS 2 messages unreleased than the Receive Maximum";
+        warn!("{}", reason);
+        return Ok(Response::Close(disconnect(connection, ReasonCode::ReceiveMaximumExceeded, reason)));
+    }
+
+    if !broker.authorize(connection.identity.as_ref().unwrap(), &header.topic_name, &qos, Action::Publish) {
+        warn!("Denied PUBLISH to {:?}", header.topic_name);
+        // MQTT 5 acks can report this, with MQTT 3.1.1 the message is either acknowledged
+        // and dropped or the client is told by losing its connection
+        let reason = "Not authorized to publish to this topic";
+        return Ok(match broker.denied_publish() {
+            DeniedPublish::Disconnect => {
+                Response::Close(disconnect(connection, ReasonCode::NotAuthorized, reason))
+            }
+            DeniedPublish::Drop => {
+                let properties = connection.reason_string(reason);
+                publish_ack(connection, &qos, id, ReasonCode::NotAuthorized, properties)
+            }
+        });
+    }
+    if qos == QoS::ExactlyOnce {
+        connection.awaiting_release.insert(id);
+    }
+    let matched = broker.publish(Message {
+        topic: header.topic_name,
+        payload: packet.payload,
+        qos: qos.clone(),
+        retain: packet.header.retain,
+        properties: packet.properties.forwarded(),
+        sender: connection.identity.as_ref().map(|i| i.client_id.clone()),
+        expiry: None,
+    });
+    let code = match matched {
+        0 => ReasonCode::NoMatchingSubscribers,
+        _ => ReasonCode::Success,
+    };
+    Ok(publish_ack(connection, &qos, id, code, Properties::default()))
+}
+
+/// What a `$share/<group>/<filter>` subscription is authorized by
+fn shared_filter(filter: &str) -> &str {
+    topic::split_shared(filter).map_or(filter, |(_, inner)| inner)
+}
+
+fn subscribe(
+    packet: MqttPacket,
+    connection: &mut Connection,
+    broker: &mut Broker,
+) -> Result<Response, &'static str> {
+    let subscription_identifier = match packet.properties.subscription_identifiers[..] {
+        [] => None,
+        [_] if !broker.subscription_identifiers_enabled() => {
+            let reason = "Subscription Identifiers are not supported";
+            return Ok(Response::Close(disconnect(connection, ReasonCode::SubscriptionIdentifiersNotSupported, reason)));
+        }
+        [id] => Some(id),
+        _ => return Err("More than one Subscription Identifier in SUBSCRIBE"),
+    };
+    let payload = packet.get_subscribe_payload()?;
+    let identity = connection.identity.as_ref().unwrap();
+    let mut retained_filters = Vec::new();
+    let mut results = Vec::new();
+    for (filter, mut options) in payload.filters {
+        options.subscription_identifier = subscription_identifier;
+        if options.qos == QoS::Reserved {
+            return Err("Invalid QoS level");
+        }
+        // The other option bits are reserved before MQTT 5
+        if connection.version != ProtocolVersion::V5 && options != SubscriptionOptions::new(options.qos.clone()) {
+            return Err("Reserved subscription option bits set");
+        }
+        if options.no_local && topic::split_shared(&filter).is_some() {
+            return Err("No Local is not allowed on shared subscriptions");
+        }
+        let existed = broker.is_subscribed(&identity.client_id, &filter);
+        let result = if let Err(e) = broker.check_topic(&filter) {
+            warn!("Rejected SUBSCRIBE to {:?}: {}", filter, e);
+            Err(ReasonCode::TopicFilterInvalid)
+        } else if broker.authorize(identity, shared_filter(&filter), &options.qos, Action::Subscribe) {
+            broker.subscribe(&identity.client_id, &filter, &options)
+        } else {
+            warn!("Denied SUBSCRIBE to {:?}", filter);
+            Err(ReasonCode::NotAuthorized)
+        };
+        let send_retained = match options.retain_handling {
+            RetainHandling::SendOnSubscribe => true,
+            RetainHandling::SendOnNewSubscribe => !existed,
+            RetainHandling::DoNotSend => false,
+        };
+        if result.is_ok() && send_retained {
+            retained_filters.push(filter);
+        }
+        results.push(result);
+    }
+    let reply = match connection.version {
+        ProtocolVersion::V5 => {
+            let codes = results.iter().map(|r| match *r {
+                Ok(ref qos) => ReasonCode::granted(qos),
+                Err(code) => code,
+            });
+            writer::v5::suback(payload.packet_id, codes.collect(), &Properties::default())
+        }
+        // MQTT 3.1 has no failure code, refused filters are acknowledged with QoS 0
+        // but never deliver anything, much like a denied PUBLISH is dropped
+        ProtocolVersion::V31 => {
+            let codes = results.iter().map(|r| match *r {
+                Ok(ref qos) => SubAckReturnCode::granted(qos),
+                Err(_) => SubAckReturnCode::MaximumQoS0,
+            });
+            writer::suback(payload.packet_id, codes.collect())
+        }
+        ProtocolVersion::V311 => {
+            let codes = results.iter().map(|r| match *r {
+                Ok(ref qos) => SubAckReturnCode::granted(qos),
+                Err(_) => SubAckReturnCode::Failure,
+            });
+            writer::suback(payload.packet_id, codes.collect())
+        }
+    };
+    broker.send(&identity.peer, reply);
+    // Retained messages may only follow the SUBACK
+    for filter in retained_filters {
+        broker.send_retained(&identity.client_id, &filter);
+    }
+    Ok(Response::None)
+}
+
+fn unsubscribe(
+    packet: MqttPacket,
+    connection: &mut Connection,
+    broker: &mut Broker,
+) -> Result<Response, &'static str> {
+    let payload = packet.get_unsubscribe_payload()?;
+    let identity = connection.identity.as_ref().unwrap();
+    let mut codes = Vec::new();
+    for filter in payload.filters {
+        codes.push(match broker.unsubscribe(&identity.client_id, &filter) {
+            true => ReasonCode::Success,
+            false => ReasonCode::NoSubscriptionExisted,
+        });
+    }
+    Ok(Response::Reply(match connection.version {
+        ProtocolVersion::V5 => writer::v5::unsuback(payload.packet_id, codes, &Properties::default()),
+        _ => writer::unsuback(payload.packet_id),
+    }))
+}
+
+/// PUBREL or PUBCOMP in the client's version
+fn release_ack(connection: &Connection, packet_type: PacketType, packet_id: u16, code: ReasonCode) -> Bytes {
+    match (connection.version, packet_type) {
+        (ProtocolVersion::V5, packet_type) => {
+            writer::v5::ack(&packet_type, packet_id, code, &Properties::default())
+        }
+        (_, PacketType::PubRel) => writer::pubrel(packet_id),
+        _ => writer::pubcomp(packet_id),
+    }
+}
+
+pub fn answer(
+    packet: MqttPacket,
+    connection: &mut Connection,
+    broker: &mut Broker,
+) -> Result<Response, &'static str> {
+    if let Some(ref identity) = connection.identity {
+        // Packets the connection had already sent when another one took its session over
+        if !broker.is_attached(&connection.context.peer, &identity.client_id) {
+            return Ok(Response::Close(None));
+        }
+    }
+    let connected = connection.identity.is_some();
+    match packet.header.packet_type {
+        PacketType::Connect if connected || connection.authentication.is_some() => {
+            Err("Duplicate CONNECT packet")
+        }
+        PacketType::Connect => connect(packet, connection, broker),
+        PacketType::Auth => auth(packet, connection, broker),
+        _ if !connected => Err("Expected CONNECT as the first packet"),
+        PacketType::Publish => publish(packet, connection, broker),
+        PacketType::PubRec if !packet.reason_code.is_some_and(|c| c.is_error()) => {
+            let id = packet_id(&packet)?;
+            broker.release(&connection.identity.as_ref().unwrap().client_id, id);
+            Ok(Response::Reply(release_ack(connection, PacketType::PubRel, id, ReasonCode::Success)))
+        }
+        // An MQTT 5 PUBREC with an error code ends the exchange like PUBCOMP
+        PacketType::PubAck | PacketType::PubComp | PacketType::PubRec => {
+            let identity = connection.identity.as_ref().unwrap();
+            broker.acknowledge(&identity.client_id, packet_id(&packet)?);
+            Ok(Response::None)
+        }
+        PacketType::PubRel => {
+            let id = packet_id(&packet)?;
+            let code = match connection.awaiting_release.remove(&id) {
+                true => ReasonCode::Success,
+                false => ReasonCode::PacketIdentifierNotFound,
+            };
+            Ok(Response::Reply(release_ack(connection, PacketType::PubComp, id, code)))
+        }
+        PacketType::Subscribe => subscribe(packet, connection, broker),
+        PacketType::Unsubscribe => unsubscribe(packet, connection, broker),
+        PacketType::PingReq => Ok(Response::Reply(writer::pingresp())),
+        PacketType::Disconnect => {
+            let identity = connection.identity.as_ref().unwrap();
+            if let Some(interval) = packet.properties.session_expiry_interval {
+                if connection.session_expiry_interval == 0 && interval != 0 {
+                    return Err("Session expiry interval set on DISCONNECT after CONNECT had none");
+                }
+                broker.set_session_expiry(&identity.client_id, interval);
+            }
+            // An MQTT 5 client may ask for its will to be published anyway
+            if packet.reason_code != Some(ReasonCode::DisconnectWithWill) {
+                broker.discard_will(&identity.client_id);
+            }
+            Ok(Response::Close(None))
+        }
+        _ => Err("Unexpected packet type from a client"),
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use futures::sync::mpsc::{self, UnboundedReceiver};
+    use config::Config;
+    use logic::*;
+    use mqtt::reader::*;
+
+    fn open(broker: &mut Broker, port: u16) -> (Connection, UnboundedReceiver<Bytes>) {
+        let peer: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        broker.open(peer, tx);
+        (Connection::new(ConnectionContext::new(peer)), rx)
+    }
+
+    /// CONNECT with a will on topic `w` whenever the will flag is set
+    fn connect_packet(level: u8, flags: u8, client_id: &str) -> MqttPacket {
+        let mut data = vec![0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, level, flags, 0x00, 0x3C];
+        if level == 5 {
+            data.push(0);
+        }
+        data.extend_from_slice(&[0x00, client_id.len() as u8]);
+        data.extend_from_slice(client_id.as_bytes());
+        if flags & 0x04 != 0 {
+            if level == 5 {
+                data.push(0);
+            }
+            data.extend_from_slice(&[0x00, 0x01, 0x77, 0x00, 0x01, 0x78]);
+        }
+        let mut packet = vec![0x10, data.len() as u8];
+        packet.extend(data);
+        read_packet(Bytes::from(packet)).unwrap()
+    }
+
+    #[test]
+    fn rejects_malformed_connect_flags() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        for &flags in &[0x1E, 0x0A, 0x22, 0x03] {
+            let (mut connection, _) = open(&mut broker, 1);
+            match answer(connect_packet(5, flags, "c"), &mut connection, &mut broker) {
+                Ok(Response::Close(Some(reply))) => {
+                    let reply = read_packet_with_version(reply, ProtocolVersion::V5).unwrap();
+                    assert_eq!(reply.reason_code, Some(ReasonCode::MalformedPacket));
+                }
+                _ => panic!("CONNECT with flags {:#x} was not refused", flags),
+            }
+            let (mut connection, _) = open(&mut broker, 2);
+            match answer(connect_packet(4, flags, "c"), &mut connection, &mut broker) {
+                Ok(Response::Close(None)) => {}
+                _ => panic!("CONNECT with flags {:#x} was not closed", flags),
+            }
+            assert!(connection.identity.is_none());
+        }
+        let (mut connection, _) = open(&mut broker, 3);
+        assert!(answer(connect_packet(4, 0x0E, "c"), &mut connection, &mut broker).is_ok());
+        assert!(connection.identity.is_some());
+    }
+
+    #[test]
+    fn ignores_connections_taken_over() {
+        let mut broker = Broker::new(&Config::default()).unwrap();
+        let (mut stale, _) = open(&mut broker, 1);
+        assert!(answer(connect_packet(4, 0x00, "c"), &mut stale, &mut broker).is_ok());
+        let (mut owner, _) = open(&mut broker, 2);
+        assert!(answer(connect_packet(4, 0x00, "c"), &mut owner, &mut broker).is_ok());
 
-pub fn answer(packet: MqttPacket) -> Result<Option<Bytes>, &'static str> {
-    Ok(Some(Bytes::from(vec![0x20, 0x02, 0x00, 0x00])))
+        // SUBSCRIBE to `z` the stale connection had pipelined
+        let subscribe = Bytes::from(vec![0x82, 0x06, 0x00, 0x01, 0x00, 0x01, 0x7A, 0x01]);
+        match answer(read_packet(subscribe).unwrap(), &mut stale, &mut broker) {
+            Ok(Response::Close(None)) => {}
+            _ => panic!("Stale connection was not closed"),
+        }
+        assert!(!broker.is_subscribed("c", "z"));
+    }
 }
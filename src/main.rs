@@ -1,105 +1,136 @@
 extern crate futures;
 extern crate tokio_core;
-extern crate tokio_io;
-extern crate tokio_proto;
-extern crate bytes;
+extern crate tokio_signal;
+extern crate clap;
+#[macro_use]
+extern crate log;
+extern crate picomq;
 
-mod mqtt;
-mod cancellable;
-mod logic;
+mod cli;
 
-use std::collections::HashMap;
+use std::process;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::iter;
-use std::net::Shutdown;
-use std::io::{Error, ErrorKind, BufReader};
+use std::io::Error;
+use std::time::Duration;
 
-use cancellable::cancellable_io_future;
-use logic::*;
-use mqtt::reader::*;
+use cli::Command;
+use picomq::{config, logging, Broker, Server};
+use config::Config;
 
-use bytes::Bytes;
 use futures::Future;
-use futures::stream::{self, Stream};
-use tokio_core::net::TcpListener;
-use tokio_core::reactor::Core;
-use tokio_io::io;
-use tokio_io::*;
+use futures::future::{self, Either};
+use futures::stream::Stream;
+use tokio_core::reactor::{Core, Handle, Timeout};
 
 fn main() {
-    start_non_secure();
+    let options = cli::parse_args();
+    let config = match options.load_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            process::exit(1);
+        }
+    };
+    let broker = match Broker::new(&config) {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            process::exit(1);
+        }
+    };
+    match options.command {
+        Command::CheckConfig => println!("Configuration is valid."),
+        Command::Serve => {
+            logging::init(&config.log);
+            run(config, broker)
+        }
+    }
 }
 
-fn start_non_secure() {
+fn run(config: Config, broker: Broker) {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
-    let addr = "0.0.0.0:1883".parse().unwrap();
-    let tcp = TcpListener::bind(&addr, &handle).unwrap();
-
-    let connections = Rc::new(RefCell::new(HashMap::new()));
-
-    let server = tcp.incoming().for_each(|(stream, addr)| {
-        let (reader, writer) = stream.split();
-        let (tx, rx) = futures::sync::mpsc::unbounded();
-        connections.borrow_mut().insert(addr, tx);
-
-        let connections_inner = connections.clone();
-        let reader = BufReader::new(reader);
-        let mut shutdown = false;
-        let iter = stream::iter_ok::<_, Error>(iter::repeat(()));
-        let socket_reader = iter.fold(reader, move |reader, _| {
-            let bytes = io::read_to_end(reader, Vec::new());
-            let bytes = bytes.and_then(|(reader, vec)| if vec.len() == 0 {
-                Err(Error::new(ErrorKind::BrokenPipe, "broken pipe"))
-            } else {
-                Ok((reader, vec))
-            });
-            let bytes = bytes.map(|(reader, vec)| (reader, read_packet(Bytes::from(vec))));
-            let connections = connections_inner.clone();
-            bytes.map(move |(reader, packet)| {
-                // TODO
-                let mut conns = connections.borrow_mut();
-                println!("Received {:#?}", packet);
-                if let Ok(packet) = packet {
-                    match answer(packet) {
-                        Ok(Some(x)) => {
-                            let tx = conns.get_mut(&addr).unwrap();
-                            tx.unbounded_send(x).unwrap();
-                        },
-                        Err(e) => {
-                            println!("Error: {}", e);
-                            shutdown = true;
-                        }
-                        _ => {},
-                    }
-                }
-                reader
-            })
-        });
-
-        let socket_writer = rx.fold(writer, |writer, msg| {
-            let amt = io::write_all(writer, msg);
-            let amt = amt.map(|(writer, _)| writer);
-            amt.map_err(|_| ())
-        });
-
-        let connections = connections.clone();
-        let mut socket_reader = cancellable_io_future(socket_reader);
-        if shutdown {
-            socket_reader.request_cancellation();
+    let server = Server::new(broker, &handle);
+    reload_on_hangup(server.broker(), &handle);
+    redirect_on_signal(server.broker(), &handle);
+
+    let mut listeners = Vec::new();
+    for listener in config.listeners.iter() {
+        match server.listen(listener, &handle) {
+            Ok((_, l)) => listeners.push(l),
+            Err(e) => {
+                error!("Cannot listen on {}: {}", listener.address, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Listeners are dropped as soon as a signal arrives, so no new connections are accepted
+    match core.run(future::join_all(listeners).select2(shutdown_signal())) {
+        Ok(Either::A(_)) => return,
+        Ok(Either::B(_)) => {}
+        Err(Either::A((e, _))) => {
+            error!("Listener error: {}", e);
+            process::exit(1);
+        }
+        Err(Either::B((e, _))) => {
+            error!("Signal handling error: {}", e);
+            process::exit(1);
         }
-        let socket_reader = socket_reader.map_err(|_| ());
-        let connection = socket_reader.map(|_| ()).select(socket_writer.map(|_| ()));
+    }
+
+    info!("Shutting down, {} connection(s) open", server.broker().borrow().connection_count());
+    let all_closed = server.shutdown(config.shutdown.publish_wills);
+    let deadline = Timeout::new(Duration::from_secs(config.shutdown.timeout), &handle).unwrap();
+    match core.run(all_closed.select2(deadline)) {
+        Ok(Either::A(_)) => info!("All connections closed"),
+        _ => warn!("Shutdown deadline reached, dropping remaining connections"),
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM received by the process
+#[cfg(unix)]
+fn shutdown_signal() -> Box<dyn Future<Item = (), Error = Error>> {
+    use tokio_signal::unix::{Signal, SIGTERM};
 
-        handle.spawn(connection.then(move |_| {
-            connections.borrow_mut().remove(&addr);
-            println!("Connection {} closed.", addr);
-            Ok(())
-        }));
+    let sigint = tokio_signal::ctrl_c().flatten_stream();
+    let sigterm = Signal::new(SIGTERM).flatten_stream().map(|_| ());
+    let first = sigint.select(sigterm).into_future();
+    Box::new(first.map(|_| ()).map_err(|(e, _)| e))
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> Box<dyn Future<Item = (), Error = Error>> {
+    let first = tokio_signal::ctrl_c().flatten_stream().into_future();
+    Box::new(first.map(|_| ()).map_err(|(e, _)| e))
+}
 
+#[cfg(unix)]
+fn reload_on_hangup(broker: Rc<RefCell<Broker>>, handle: &Handle) {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    let hangups = Signal::new(SIGHUP).flatten_stream().for_each(move |_| {
+        broker.borrow_mut().reload();
         Ok(())
     });
+    handle.spawn(hangups.map_err(|e| error!("Signal handling error: {}", e)));
+}
 
-    core.run(server).unwrap();
+#[cfg(not(unix))]
+fn reload_on_hangup(_: Rc<RefCell<Broker>>, _: &Handle) {}
+
+/// SIGUSR1 starts or stops sending clients to another server
+#[cfg(unix)]
+fn redirect_on_signal(broker: Rc<RefCell<Broker>>, handle: &Handle) {
+    use tokio_signal::unix::{Signal, SIGUSR1};
+
+    let signals = Signal::new(SIGUSR1).flatten_stream().for_each(move |_| {
+        broker.borrow_mut().toggle_redirect();
+        Ok(())
+    });
+    handle.spawn(signals.map_err(|e| error!("Signal handling error: {}", e)));
 }
+
+#[cfg(not(unix))]
+fn redirect_on_signal(_: Rc<RefCell<Broker>>, _: &Handle) {}
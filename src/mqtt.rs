@@ -7,7 +7,12 @@ extern crate tokio_proto;
 use bytes::Bytes;
 use std::fmt;
 use std::str::from_utf8;
-use std::collections::HashMap;
+
+pub mod properties;
+pub mod reason;
+
+pub use self::properties::Properties;
+pub use self::reason::ReasonCode;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum PacketType {
@@ -25,9 +30,54 @@ pub enum PacketType {
     PingReq,
     PingResp,
     Disconnect,
+    Auth,
     Reserved,
 }
 
+impl fmt::Display for PacketType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            PacketType::Connect => "CONNECT",
+            PacketType::ConnAck => "CONNACK",
+            PacketType::Publish => "PUBLISH",
+            PacketType::PubAck => "PUBACK",
+            PacketType::PubRec => "PUBREC",
+            PacketType::PubRel => "PUBREL",
+            PacketType::PubComp => "PUBCOMP",
+            PacketType::Subscribe => "SUBSCRIBE",
+            PacketType::SubAck => "SUBACK",
+            PacketType::Unsubscribe => "UNSUBSCRIBE",
+            PacketType::UnsubAck => "UNSUBACK",
+            PacketType::PingReq => "PINGREQ",
+            PacketType::PingResp => "PINGRESP",
+            PacketType::Disconnect => "DISCONNECT",
+            PacketType::Auth => "AUTH",
+            PacketType::Reserved => "RESERVED",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Negotiated per connection by the protocol level in CONNECT
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ProtocolVersion {
+    /// "MQIsdp" at protocol level 3
+    V31,
+    V311,
+    V5,
+}
+
+impl ProtocolVersion {
+    pub fn from_connect(protocol_name: &str, protocol_level: u8) -> Option<ProtocolVersion> {
+        match (protocol_name, protocol_level) {
+            ("MQIsdp", 3) => Some(ProtocolVersion::V31),
+            ("MQTT", 4) => Some(ProtocolVersion::V311),
+            ("MQTT", 5) => Some(ProtocolVersion::V5),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum QoS {
     AtMostOnce,
@@ -37,7 +87,7 @@ pub enum QoS {
 }
 
 impl QoS {
-    fn from_byte(byte: u8, right_shift: u8) -> QoS {
+    pub fn from_byte(byte: u8, right_shift: u8) -> QoS {
         match (byte >> right_shift) & 0x03 {
             0 => QoS::AtMostOnce,
             1 => QoS::AtLeastOnce,
@@ -45,6 +95,15 @@ impl QoS {
             _ => QoS::Reserved,
         }
     }
+
+    pub fn to_byte(&self) -> u8 {
+        match *self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+            QoS::ExactlyOnce => 2,
+            QoS::Reserved => panic!("Reserved QoS should not be used"),
+        }
+    }
 }
 
 #[derive(PartialEq)]
@@ -79,9 +138,46 @@ impl fmt::Debug for FixedHeader {
 pub struct MqttPacket {
     pub header: FixedHeader,
     pub var_header: VariableHeader,
+    /// MQTT 5 only, for the acks, DISCONNECT and AUTH it is absent when omitted
+    pub reason_code: Option<ReasonCode>,
+    /// MQTT 5 only, empty for MQTT 3.1.1 packets
+    pub properties: Properties,
     pub payload: Bytes,
 }
 
+/// One-line summary which leaves out the payload contents
+impl fmt::Display for MqttPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.header.packet_type)?;
+        match self.var_header {
+            VariableHeader::Connect(ref h) => write!(
+                f,
+                " protocol={}/{} keep_alive={} clean_session={}",
+                h.protocol_name,
+                h.protocol_level,
+                h.keep_alive,
+                h.clean_session()
+            )?,
+            VariableHeader::ConnAck(ref h) => write!(f, " return_code={:?}", h.return_code)?,
+            VariableHeader::Publish(ref h) => write!(
+                f,
+                " topic={:?} qos={:?} dup={} retain={} packet_id={}",
+                h.topic_name,
+                self.header.qos,
+                self.header.dup,
+                self.header.retain,
+                h.packet_id
+            )?,
+            VariableHeader::WithPacketId(id) => write!(f, " packet_id={}", id)?,
+            VariableHeader::None => {}
+        }
+        if let Some(code) = self.reason_code {
+            write!(f, " reason_code={:?}", code)?;
+        }
+        write!(f, " payload_len={}", self.payload.len())
+    }
+}
+
 /* Fixed-length headers for different packet types */
 #[derive(Debug, PartialEq, Clone)]
 pub enum VariableHeader {
@@ -123,15 +219,32 @@ impl ConnectHeader {
     pub fn clean_session(&self) -> bool {
         self.get_flag(0b00000010)
     }
+    /// Rejects flag combinations the spec calls malformed
+    pub fn check_flags(&self) -> Result<(), &'static str> {
+        if self.get_flag(0b00000001) {
+            return Err("Reserved flag is set in CONNECT");
+        }
+        if !self.has_will_flag() && (self.will_retain() || self.will_qos() != QoS::AtMostOnce) {
+            return Err("Will QoS or retain is set without a will");
+        }
+        if self.will_qos() == QoS::Reserved {
+            return Err("Invalid will QoS level");
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Default)]
 pub struct ConnectPayload {
     pub client_id: String,
     pub will_topic: Option<String>,
-    pub will_message: Option<String>,
+    /// Binary data, unlike the other fields
+    pub will_message: Option<Bytes>,
     pub username: Option<String>,
-    pub password: Option<String>,
+    /// Binary data, unlike the other fields
+    pub password: Option<Bytes>,
+    /// MQTT 5 only
+    pub will_properties: Properties,
 }
 
 /* CONNACK */
@@ -159,7 +272,8 @@ impl ConnAckReturnCode {
             1 => ConnAckReturnCode::UnacceptableProtocol,
             2 => ConnAckReturnCode::IdentifierRejected,
             3 => ConnAckReturnCode::ServerUnavailable,
-            4 => ConnAckReturnCode::NotAuthorized,
+            4 => ConnAckReturnCode::BadAuth,
+            5 => ConnAckReturnCode::NotAuthorized,
             _ => ConnAckReturnCode::Reserved,
         }
     }
@@ -170,7 +284,8 @@ impl ConnAckReturnCode {
             ConnAckReturnCode::UnacceptableProtocol => 1,
             ConnAckReturnCode::IdentifierRejected => 2,
             ConnAckReturnCode::ServerUnavailable => 3,
-            ConnAckReturnCode::NotAuthorized => 4,
+            ConnAckReturnCode::BadAuth => 4,
+            ConnAckReturnCode::NotAuthorized => 5,
             _ => panic!("Reserved return code should not be used"),
         }
     }
@@ -190,25 +305,64 @@ pub struct PublishHeader {
 }
 
 /* SUBSCRIBE */
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct SubscribePayload {
     pub packet_id: u16,
-    pub filters: HashMap<String, QoS>,
+    /// In packet order, SUBACK return codes have to follow it
+    pub filters: Vec<(String, SubscriptionOptions)>,
 }
 
-impl Clone for SubscribePayload {
-    fn clone(&self) -> Self {
-        let packet_id = &self.packet_id;
-        let filters_to_copy = &self.filters;
+/// When retained messages are sent for a new subscription
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RetainHandling {
+    SendOnSubscribe,
+    /// Only if the subscription did not exist yet
+    SendOnNewSubscribe,
+    DoNotSend,
+}
 
-        let mut filters = HashMap::new();
-        for (key, val) in filters_to_copy.iter() {
-            filters.insert(key.clone().to_string(), val.clone());
+/// Options byte of a SUBSCRIBE filter, everything but the QoS is MQTT 5 only
+#[derive(Debug, PartialEq, Clone)]
+pub struct SubscriptionOptions {
+    pub qos: QoS,
+    /// Messages the subscriber published itself are not sent back to it
+    pub no_local: bool,
+    /// Forwarded messages keep their RETAIN flag
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+    /// From the SUBSCRIBE properties, sent back with the messages the subscription matches
+    pub subscription_identifier: Option<u32>,
+}
+
+impl SubscriptionOptions {
+    /// What an MQTT 3.1.1 subscription means
+    pub fn new(qos: QoS) -> SubscriptionOptions {
+        SubscriptionOptions {
+            qos: qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::SendOnSubscribe,
+            subscription_identifier: None,
         }
-        SubscribePayload {
-            packet_id: *packet_id,
-            filters: filters,
+    }
+
+    pub fn from_byte(byte: u8) -> Result<SubscriptionOptions, &'static str> {
+        if byte & 0xC0 != 0 {
+            return Err("Reserved subscription option bits set");
         }
+        let retain_handling = match (byte >> 4) & 0x03 {
+            0 => RetainHandling::SendOnSubscribe,
+            1 => RetainHandling::SendOnNewSubscribe,
+            2 => RetainHandling::DoNotSend,
+            _ => return Err("Invalid Retain Handling option"),
+        };
+        Ok(SubscriptionOptions {
+            qos: QoS::from_byte(byte, 0),
+            no_local: byte & 0x04 != 0,
+            retain_as_published: byte & 0x08 != 0,
+            retain_handling: retain_handling,
+            subscription_identifier: None,
+        })
     }
 }
 
@@ -242,22 +396,32 @@ impl SubAckReturnCode {
             _ => panic!("Reserved return code should not be used"),
         }
     }
+
+    pub fn granted(qos: &QoS) -> SubAckReturnCode {
+        match *qos {
+            QoS::AtMostOnce => SubAckReturnCode::MaximumQoS0,
+            QoS::AtLeastOnce => SubAckReturnCode::MaximumQoS1,
+            QoS::ExactlyOnce => SubAckReturnCode::MaximumQoS2,
+            QoS::Reserved => SubAckReturnCode::Failure,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct SubAckPayload {
-    packet_id: u16,
-    return_codes: Vec<SubAckReturnCode>,
+    pub packet_id: u16,
+    pub return_codes: Vec<SubAckReturnCode>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct UnsubscribePayload {
-    packet_id: u16,
-    filters: Vec<String>,
+    pub packet_id: u16,
+    pub filters: Vec<String>,
 }
 
 pub mod reader {
     use mqtt::*;
+    use topic;
 
     fn to_u16(msb: u8, lsb: u8) -> u16 {
         lsb as u16 | (msb as u16) << 8
@@ -317,6 +481,19 @@ pub mod reader {
         }
     }
 
+    fn binary_scan(bytes: &mut Bytes) -> Option<Bytes> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        let data_len = to_u16(bytes[0], bytes[1]) as usize;
+        if bytes.len() < data_len + 2 {
+            return None;
+        }
+        let result = bytes.slice(2, 2 + data_len);
+        bytes.advance(data_len + 2);
+        Some(result)
+    }
+
     /* Helper functions */
     fn read_packet_type(b: u8) -> PacketType {
         let value = (b & 0xF0) >> 4;
@@ -335,6 +512,7 @@ pub mod reader {
             12 => PacketType::PingReq,
             13 => PacketType::PingResp,
             14 => PacketType::Disconnect,
+            15 => PacketType::Auth,
             _ => PacketType::Reserved,
         }
     }
@@ -380,77 +558,92 @@ pub mod reader {
         })
     }
 
-    fn construct_packet(header: FixedHeader) -> Result<MqttPacket, &'static str> {
+    fn construct_packet(
+        header: FixedHeader,
+        version: ProtocolVersion,
+    ) -> Result<MqttPacket, &'static str> {
         // TODO: Remove clone()
-        let bytes = header.payload.clone();
-        match header.packet_type {
-            // CONNECT
+        let mut bytes = header.payload.clone();
+        let v5 = version == ProtocolVersion::V5;
+        let mut reason_code = None;
+        let mut properties = Properties::default();
+        let var_header = match header.packet_type {
+            // CONNECT, which carries the version itself
             PacketType::Connect => {
                 if bytes.len() < 4 {
                     return Err("Not enough data supplied");
                 }
-                if let Some(protocol_name) = utf8_safe_decode(&bytes) {
-                    let proto_name_len = protocol_name.len();
-                    if bytes.len() < proto_name_len + 6 {
-                        return Err("Not enough data supplied");
-                    }
-                    let protocol_level = bytes[proto_name_len + 2];
-                    let flag_bits = bytes[proto_name_len + 3];
-                    let keep_alive = to_u16(bytes[proto_name_len + 4], bytes[proto_name_len + 5]);
-                    let var_header = VariableHeader::Connect(ConnectHeader {
-                        protocol_name: protocol_name,
-                        protocol_level: protocol_level,
-                        flag_bits: flag_bits,
-                        keep_alive: keep_alive,
-                    });
-                    return Ok(MqttPacket {
-                        header: header,
-                        var_header: var_header,
-                        payload: bytes.slice_from(proto_name_len + 6),
-                    });
-                }
-                Err("Invalid UTF-8 sequence in protocol name")
+                let protocol_name = match utf8_safe_decode(&bytes) {
+                    Some(name) => name,
+                    None => return Err("Invalid UTF-8 sequence in protocol name"),
+                };
+                let proto_name_len = protocol_name.len();
+                if bytes.len() < proto_name_len + 6 {
+                    return Err("Not enough data supplied");
+                }
+                let protocol_level = bytes[proto_name_len + 2];
+                let flag_bits = bytes[proto_name_len + 3];
+                let keep_alive = to_u16(bytes[proto_name_len + 4], bytes[proto_name_len + 5]);
+                bytes.advance(proto_name_len + 6);
+                if protocol_level == 5 {
+                    properties = properties::read_properties(&mut bytes)?;
+                }
+                VariableHeader::Connect(ConnectHeader {
+                    protocol_name: protocol_name,
+                    protocol_level: protocol_level,
+                    flag_bits: flag_bits,
+                    keep_alive: keep_alive,
+                })
             }
             // CONNACK
             PacketType::ConnAck => {
-                match bytes.len() {
-                    2 => {
-                        let var_header = VariableHeader::ConnAck(ConnAckHeader {
-                            flags: bytes[0],
-                            return_code: ConnAckReturnCode::from_byte(bytes[1]),
-                        });
-                        return Ok(MqttPacket {
-                            header: header,
-                            var_header: var_header,
-                            payload: Bytes::new(),
-                        });
-                    }
-                    _ => Err("Invalid data supplied"),
+                if bytes.len() < 2 || (!v5 && bytes.len() != 2) {
+                    return Err("Invalid data supplied");
                 }
+                let flags = bytes[0];
+                let code = bytes[1];
+                bytes.advance(2);
+                let return_code = if v5 {
+                    let code = ReasonCode::from_byte(code)?;
+                    reason_code = Some(code);
+                    properties = properties::read_properties(&mut bytes)?;
+                    code.to_connack_return_code()
+                } else {
+                    ConnAckReturnCode::from_byte(code)
+                };
+                VariableHeader::ConnAck(ConnAckHeader {
+                    flags: flags,
+                    return_code: return_code,
+                })
             }
             // PUBLISH
             PacketType::Publish => {
-                if (header.qos != QoS::AtMostOnce && bytes.len() < 6) || bytes.len() < 4 {
+                if !v5 && ((header.qos != QoS::AtMostOnce && bytes.len() < 6) || bytes.len() < 4) {
                     return Err("Not enough data supplied");
                 }
-                if let Some(topic) = utf8_safe_decode(&bytes) {
-                    let topic_len = topic.len();
-                    let mut packet_id = 0u16;
-                    let mut offset = topic_len + 2;
-                    if header.qos != QoS::AtMostOnce {
-                        packet_id = to_u16(bytes[offset], bytes[offset + 1]);
-                        offset += 2;
+                let topic = match utf8_safe_scan(&mut bytes) {
+                    Some(topic) => topic,
+                    None => return Err("Invalid UTF-8 sequence"),
+                };
+                let mut packet_id = 0u16;
+                if header.qos != QoS::AtMostOnce {
+                    if bytes.len() < 2 {
+                        return Err("Not enough data supplied");
                     }
-                    return Ok(MqttPacket {
-                        header: header,
-                        var_header: VariableHeader::Publish(PublishHeader {
-                            topic_name: topic,
-                            packet_id: packet_id,
-                        }),
-                        payload: bytes.slice_from(offset),
-                    });
+                    packet_id = to_u16(bytes[0], bytes[1]);
+                    bytes.advance(2);
+                }
+                if v5 {
+                    properties = properties::read_properties(&mut bytes)?;
+                }
+                // An empty topic name stands for the one the topic alias was set to
+                if !topic.is_empty() || properties.topic_alias.is_none() {
+                    topic::validate_name(&topic)?;
                 }
-                Err("Invalid UTF-8 sequence")
+                VariableHeader::Publish(PublishHeader {
+                    topic_name: topic,
+                    packet_id: packet_id,
+                })
             }
             PacketType::PubAck | PacketType::PubRec | PacketType::PubRel |
             PacketType::PubComp | PacketType::Subscribe | PacketType::SubAck |
@@ -458,22 +651,56 @@ pub mod reader {
                 if bytes.len() < 2 {
                     return Err("Not enough data supplied");
                 }
-                Ok(MqttPacket {
-                    header: header,
-                    var_header: VariableHeader::WithPacketId(to_u16(bytes[0], bytes[1])),
-                    payload: bytes.slice_from(2),
-                })
+                let packet_id = to_u16(bytes[0], bytes[1]);
+                bytes.advance(2);
+                if v5 {
+                    match header.packet_type {
+                        // The reason code and the properties may be left out
+                        PacketType::PubAck | PacketType::PubRec | PacketType::PubRel |
+                        PacketType::PubComp => if !bytes.is_empty() {
+                            reason_code = Some(ReasonCode::from_byte(bytes[0])?);
+                            bytes.advance(1);
+                            if !bytes.is_empty() {
+                                properties = properties::read_properties(&mut bytes)?;
+                            }
+                        },
+                        _ => properties = properties::read_properties(&mut bytes)?,
+                    }
+                }
+                VariableHeader::WithPacketId(packet_id)
             }
-            _ => Ok(MqttPacket {
-                header: header,
-                var_header: VariableHeader::None,
-                payload: bytes,
-            }),
-        }
+            PacketType::Auth if !v5 => return Err("Invalid packet type"),
+            PacketType::Disconnect | PacketType::Auth if v5 => {
+                if !bytes.is_empty() {
+                    reason_code = Some(ReasonCode::from_byte(bytes[0])?);
+                    bytes.advance(1);
+                    if !bytes.is_empty() {
+                        properties = properties::read_properties(&mut bytes)?;
+                    }
+                }
+                VariableHeader::None
+            }
+            _ => VariableHeader::None,
+        };
+        Ok(MqttPacket {
+            header: header,
+            var_header: var_header,
+            reason_code: reason_code,
+            properties: properties,
+            payload: bytes,
+        })
     }
 
+    /// Reads a packet as MQTT 3.1.1, which is what CONNECT is read with
     pub fn read_packet(bytes: Bytes) -> Result<MqttPacket, &'static str> {
-        read_header(&bytes).and_then(|h| construct_packet(h))
+        read_packet_with_version(bytes, ProtocolVersion::V311)
+    }
+
+    pub fn read_packet_with_version(
+        bytes: Bytes,
+        version: ProtocolVersion,
+    ) -> Result<MqttPacket, &'static str> {
+        read_header(&bytes).and_then(|h| construct_packet(h, version))
     }
 
     /* Packet-type related payload reading */
@@ -496,10 +723,14 @@ pub mod reader {
             }
 
             if head.has_will_flag() {
+                if head.protocol_level == 5 {
+                    result.will_properties = properties::read_properties(&mut bytes)?;
+                }
                 let topic = utf8_safe_scan(&mut bytes);
-                let message = utf8_safe_scan(&mut bytes);
+                let message = binary_scan(&mut bytes);
                 match (topic, message) {
                     (Some(t), Some(msg)) => {
+                        topic::validate_name(&t)?;
                         result.will_topic = Some(t);
                         result.will_message = Some(msg);
                     }
@@ -515,7 +746,7 @@ pub mod reader {
             }
 
             if head.has_password_flag() {
-                match utf8_safe_scan(&mut bytes) {
+                match binary_scan(&mut bytes) {
                     Some(p) => result.password = Some(p),
                     _ => return Err("Password flag is defined, but no password is found"),
                 }
@@ -536,19 +767,20 @@ pub mod reader {
                 return Err("No payload found");
             }
             let mut bytes = self.payload.clone();
-            let mut filters = HashMap::<String, QoS>::new();
+            let mut filters = Vec::<(String, SubscriptionOptions)>::new();
             loop {
                 if bytes.len() < 2 {
                     break;
                 }
                 match utf8_safe_scan(&mut bytes) {
                     Some(filter) => {
+                        topic::validate_filter(&filter)?;
                         if bytes.len() == 0 {
                             return Err("Unexpected end of stream");
                         }
-                        let qos = QoS::from_byte(bytes[0], 0);
+                        let options = SubscriptionOptions::from_byte(bytes[0])?;
                         bytes.advance(1);
-                        filters.insert(filter, qos);
+                        filters.push((filter, options));
                     }
                     None => return Err("Found invalid UTF-8 sequence"),
                 }
@@ -600,7 +832,10 @@ pub mod reader {
                     break;
                 }
                 match utf8_safe_scan(&mut bytes) {
-                    Some(filter) => filters.push(filter),
+                    Some(filter) => {
+                        topic::validate_filter(&filter)?;
+                        filters.push(filter);
+                    }
                     None => return Err("Found invalid UTF-8 sequence"),
                 }
             }
@@ -618,7 +853,6 @@ pub mod reader {
     mod tests {
         use std::collections::HashMap;
         use bytes::Bytes;
-        use mqtt::*;
         use mqtt::reader::*;
         use mqtt::VariableHeader::*;
 
@@ -640,7 +874,7 @@ pub mod reader {
                 (0xC0, PacketType::PingReq),
                 (0xD0, PacketType::PingResp),
                 (0xE0, PacketType::Disconnect),
-                (0xF0, PacketType::Reserved),
+                (0xF0, PacketType::Auth),
             ].into_iter()
                 .collect();
 
@@ -740,10 +974,127 @@ pub mod reader {
                     assert_eq!(p.username, Option::None);
                     assert_eq!(p.password, Option::None);
                 }
-                Err(r) => panic!(r),
+                Err(r) => panic!("{:?}", r),
             }
         }
 
+        #[test]
+        fn checks_connect_flags() {
+            let header = |flags: u8| {
+                let data = Bytes::from(vec![
+                    0x10, 0x0C, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, flags, 0x00, 0x05, 0x00, 0x00,
+                ]);
+                match read_packet(data).unwrap().var_header {
+                    Connect(h) => h,
+                    _ => panic!(),
+                }
+            };
+            assert_eq!(header(0x02).check_flags(), Ok(()));
+            assert_eq!(header(0x2E).check_flags(), Ok(()));
+            assert_eq!(header(0x03).check_flags(), Err("Reserved flag is set in CONNECT"));
+            assert_eq!(header(0x0A).check_flags(), Err("Will QoS or retain is set without a will"));
+            assert_eq!(header(0x22).check_flags(), Err("Will QoS or retain is set without a will"));
+            assert_eq!(header(0x1E).check_flags(), Err("Invalid will QoS level"));
+        }
+
+        #[test]
+        fn reads_mqtt_3_1_connect_packet() {
+            // CONNECT, protocol name = MQIsdp, protocol level = 3, client ID = "paho"
+            let data = Bytes::from(vec![
+                0x10, 0x14, 0x00, 0x06, 0x4D, 0x51, 0x49, 0x73, 0x64, 0x70, 0x03, 0x02, 0x00, 0x05,
+                0x00, 0x04, 0x70, 0x61, 0x68, 0x6f,
+            ]);
+            let packet = read_packet(data).unwrap();
+            match packet.var_header.clone() {
+                Connect(h) => {
+                    assert_eq!(h.protocol_name, "MQIsdp");
+                    assert_eq!(h.protocol_level, 3);
+                    assert_eq!(ProtocolVersion::from_connect(&h.protocol_name, h.protocol_level),
+                        Some(ProtocolVersion::V31));
+                }
+                _ => panic!(),
+            }
+            assert_eq!(packet.get_connect_payload().unwrap().client_id, "paho");
+            assert_eq!(ProtocolVersion::from_connect("MQTT", 3), Option::None);
+            assert_eq!(ProtocolVersion::from_connect("MQIsdp", 4), Option::None);
+        }
+
+        #[test]
+        fn reads_binary_will_message_and_password() {
+            // CONNECT with will and password flags, neither of them valid UTF-8
+            let data = Bytes::from(vec![
+                0x10, 0x18,
+                0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x04, 0xC6, 0x00, 0x3C,
+                0x00, 0x01, 0x63,
+                0x00, 0x01, 0x74,
+                0x00, 0x02, 0xFF, 0x00,
+                0x00, 0x01, 0x75,
+                0x00, 0x01, 0xC3,
+            ]);
+            let payload = read_packet(data).unwrap().get_connect_payload().unwrap();
+            assert_eq!(payload.will_topic, Some("t".to_string()));
+            assert_eq!(payload.will_message, Some(Bytes::from(vec![0xFF, 0x00])));
+            assert_eq!(payload.username, Some("u".to_string()));
+            assert_eq!(payload.password, Some(Bytes::from(vec![0xC3])));
+        }
+
+        #[test]
+        fn reads_v5_connect_packet() {
+            // Level 5, will flag, session expiry 60, will delay 5, client ID "a", will "w" -> "x"
+            let data = Bytes::from(vec![
+                0x10, 0x1D, 0x00, 0x04, 0x4D, 0x51, 0x54, 0x54, 0x05, 0x04, 0x00, 0x3C,
+                0x05, 0x11, 0x00, 0x00, 0x00, 0x3C,
+                0x00, 0x01, 0x61,
+                0x05, 0x18, 0x00, 0x00, 0x00, 0x05,
+                0x00, 0x01, 0x77, 0x00, 0x01, 0x78,
+            ]);
+            let packet = read_packet(data).unwrap();
+            assert_eq!(packet.properties.session_expiry_interval, Some(60));
+            let payload = packet.get_connect_payload().unwrap();
+            assert_eq!(payload.client_id, "a");
+            assert_eq!(payload.will_properties.will_delay_interval, Some(5));
+            assert_eq!(payload.will_topic, Some("w".to_string()));
+            assert_eq!(payload.will_message, Some(Bytes::from(&b"x"[..])));
+        }
+
+        #[test]
+        fn reads_auth_packet_only_in_v5() {
+            let data = Bytes::from(vec![0xF0, 0x01, 0x18]);
+            assert_eq!(read_packet(data.clone()), Err("Invalid packet type"));
+            let packet = read_packet_with_version(data, ProtocolVersion::V5).unwrap();
+            assert_eq!(packet.header.packet_type, PacketType::Auth);
+            assert_eq!(packet.reason_code, Some(ReasonCode::ContinueAuthentication));
+        }
+
+        #[test]
+        fn reads_v5_subscription_options() {
+            // SUBSCRIBE id 1, no properties, "a" with QoS 1, No Local, Retain As Published,
+            // Retain Handling 2
+            let data = Bytes::from(vec![0x82, 0x07, 0x00, 0x01, 0x00, 0x00, 0x01, 0x61, 0x2D]);
+            let packet = read_packet_with_version(data, ProtocolVersion::V5).unwrap();
+            let payload = packet.get_subscribe_payload().unwrap();
+            assert_eq!(payload.filters, vec![("a".to_string(), SubscriptionOptions {
+                qos: QoS::AtLeastOnce,
+                no_local: true,
+                retain_as_published: true,
+                retain_handling: RetainHandling::DoNotSend,
+                subscription_identifier: Option::None,
+            })]);
+            assert_eq!(SubscriptionOptions::from_byte(0x30), Err("Invalid Retain Handling option"));
+            assert_eq!(SubscriptionOptions::from_byte(0x40), Err("Reserved subscription option bits set"));
+        }
+
+        #[test]
+        fn rejects_invalid_topics() {
+            // PUBLISH to "a/#"
+            let data = Bytes::from(vec![0x30, 0x05, 0x00, 0x03, 0x61, 0x2F, 0x23]);
+            assert_eq!(read_packet(data), Err("Wildcards are not allowed in topic names"));
+            // SUBSCRIBE to "a#"
+            let data = Bytes::from(vec![0x82, 0x07, 0x00, 0x01, 0x00, 0x02, 0x61, 0x23, 0x00]);
+            let err = read_packet(data).unwrap().get_subscribe_payload().unwrap_err();
+            assert_eq!(err, "Multi-level wildcard must be the last level on its own");
+        }
+
         #[test]
         fn reads_connack_packet() {
             // CONNACK, no session present, connection accepted
@@ -783,8 +1134,7 @@ pub mod reader {
                 0x00,
             ]);
             let packet = read_packet(data).unwrap();
-            let mut filters = HashMap::new();
-            filters.insert("SampleTopic".to_string(), QoS::AtMostOnce);
+            let filters = vec![("SampleTopic".to_string(), SubscriptionOptions::new(QoS::AtMostOnce))];
 
             assert_eq!(packet.header.packet_type, PacketType::Subscribe);
             match packet.var_header.clone() {
@@ -797,7 +1147,7 @@ pub mod reader {
                     assert_eq!(p.filters, filters);
                     assert_eq!(p.packet_id, 1);
                 }
-                Err(r) => panic!(r),
+                Err(r) => panic!("{:?}", r),
             }
         }
 
@@ -901,6 +1251,32 @@ pub mod reader {
             assert_eq!(packet.payload, &payload);
         }
 
+        #[test]
+        fn summarizes_packet_without_payload() {
+            // PUBLISH, QoS = 1, topic: a/b, packet ID = 10, payload = Hello
+            let data = Bytes::from(vec![
+                0x33,
+                0x30,
+                0x00,
+                0x03,
+                0x61,
+                0x2F,
+                0x62,
+                0x00,
+                0xA,
+                0x48,
+                0x65,
+                0x6C,
+                0x6C,
+                0x6F,
+            ]);
+            let packet = read_packet(data).unwrap();
+            assert_eq!(
+                packet.to_string(),
+                "PUBLISH topic=\"a/b\" qos=AtLeastOnce dup=false retain=true packet_id=10 payload_len=5"
+            );
+        }
+
         #[test]
         fn reads_unsubscribe_packet() {
             // UNSUBSCRIBE, packet ID = 1, topic "SampleTopic"
@@ -937,8 +1313,279 @@ pub mod reader {
                     assert_eq!(p.filters, filters);
                     assert_eq!(p.packet_id, 1);
                 }
-                Err(r) => panic!(r),
+                Err(r) => panic!("{:?}", r),
+            }
+        }
+    }
+}
+
+pub mod writer {
+    use mqtt::*;
+
+    fn vlq(mut value: u32) -> Vec<u8> {
+        let mut result = Vec::with_capacity(4);
+        loop {
+            let mut byte = (value % 128) as u8;
+            value /= 128;
+            if value > 0 {
+                byte |= 0x80;
+            }
+            result.push(byte);
+            if value == 0 {
+                return result;
+            }
+        }
+    }
+
+    fn u16_bytes(value: u16) -> [u8; 2] {
+        [(value >> 8) as u8, value as u8]
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut result = u16_bytes(value.len() as u16).to_vec();
+        result.extend_from_slice(value.as_bytes());
+        result
+    }
+
+    fn packet(first_byte: u8, body: &[u8]) -> Bytes {
+        let mut result = vec![first_byte];
+        result.extend(vlq(body.len() as u32));
+        result.extend_from_slice(body);
+        Bytes::from(result)
+    }
+
+    pub fn connack(session_present: bool, return_code: ConnAckReturnCode) -> Bytes {
+        packet(0x20, &[session_present as u8, return_code.to_byte()])
+    }
+
+    pub fn publish(
+        topic: &str,
+        packet_id: u16,
+        qos: &QoS,
+        retain: bool,
+        dup: bool,
+        payload: &[u8],
+    ) -> Bytes {
+        let first_byte = 0x30 | (dup as u8) << 3 | qos.to_byte() << 1 | retain as u8;
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+        body.extend(string(topic));
+        if *qos != QoS::AtMostOnce {
+            body.extend(u16_bytes(packet_id).iter());
+        }
+        body.extend_from_slice(payload);
+        packet(first_byte, &body)
+    }
+
+    pub fn puback(packet_id: u16) -> Bytes {
+        packet(0x40, &u16_bytes(packet_id))
+    }
+
+    pub fn pubrec(packet_id: u16) -> Bytes {
+        packet(0x50, &u16_bytes(packet_id))
+    }
+
+    pub fn pubrel(packet_id: u16) -> Bytes {
+        packet(0x62, &u16_bytes(packet_id))
+    }
+
+    pub fn pubcomp(packet_id: u16) -> Bytes {
+        packet(0x70, &u16_bytes(packet_id))
+    }
+
+    pub fn suback(packet_id: u16, return_codes: Vec<SubAckReturnCode>) -> Bytes {
+        let mut body = u16_bytes(packet_id).to_vec();
+        body.extend(return_codes.into_iter().map(|c| c.to_byte()));
+        packet(0x90, &body)
+    }
+
+    pub fn unsuback(packet_id: u16) -> Bytes {
+        packet(0xB0, &u16_bytes(packet_id))
+    }
+
+    pub fn pingresp() -> Bytes {
+        packet(0xD0, &[])
+    }
+
+    /* MQTT 5 variants, which add reason codes and properties */
+    pub mod v5 {
+        use mqtt::*;
+        use mqtt::properties::write_properties;
+        use super::{packet, string, u16_bytes};
+
+        /// Reason code and properties, which may be left out when there is nothing to say
+        fn optional_tail(reason_code: ReasonCode, properties: &Properties, body: &mut Vec<u8>) {
+            if reason_code != ReasonCode::Success || !properties.is_empty() {
+                body.push(reason_code.to_byte());
+                if !properties.is_empty() {
+                    write_properties(properties, body);
+                }
             }
         }
+
+        pub fn connack(session_present: bool, reason_code: ReasonCode, properties: &Properties) -> Bytes {
+            let mut body = vec![session_present as u8, reason_code.to_byte()];
+            write_properties(properties, &mut body);
+            packet(0x20, &body)
+        }
+
+        pub fn publish(
+            topic: &str,
+            packet_id: u16,
+            qos: &QoS,
+            retain: bool,
+            dup: bool,
+            properties: &Properties,
+            payload: &[u8],
+        ) -> Bytes {
+            let first_byte = 0x30 | (dup as u8) << 3 | qos.to_byte() << 1 | retain as u8;
+            let mut body = Vec::with_capacity(topic.len() + payload.len() + 5);
+            body.extend(string(topic));
+            if *qos != QoS::AtMostOnce {
+                body.extend(u16_bytes(packet_id).iter());
+            }
+            write_properties(properties, &mut body);
+            body.extend_from_slice(payload);
+            packet(first_byte, &body)
+        }
+
+        /// PUBACK, PUBREC, PUBREL or PUBCOMP
+        pub fn ack(
+            packet_type: &PacketType,
+            packet_id: u16,
+            reason_code: ReasonCode,
+            properties: &Properties,
+        ) -> Bytes {
+            let first_byte = match *packet_type {
+                PacketType::PubAck => 0x40,
+                PacketType::PubRec => 0x50,
+                PacketType::PubRel => 0x62,
+                PacketType::PubComp => 0x70,
+                _ => panic!("Not an acknowledgement packet type"),
+            };
+            let mut body = u16_bytes(packet_id).to_vec();
+            optional_tail(reason_code, properties, &mut body);
+            packet(first_byte, &body)
+        }
+
+        pub fn suback(packet_id: u16, reason_codes: Vec<ReasonCode>, properties: &Properties) -> Bytes {
+            let mut body = u16_bytes(packet_id).to_vec();
+            write_properties(properties, &mut body);
+            body.extend(reason_codes.into_iter().map(|c| c.to_byte()));
+            packet(0x90, &body)
+        }
+
+        pub fn unsuback(packet_id: u16, reason_codes: Vec<ReasonCode>, properties: &Properties) -> Bytes {
+            let mut body = u16_bytes(packet_id).to_vec();
+            write_properties(properties, &mut body);
+            body.extend(reason_codes.into_iter().map(|c| c.to_byte()));
+            packet(0xB0, &body)
+        }
+
+        pub fn disconnect(reason_code: ReasonCode, properties: &Properties) -> Bytes {
+            let mut body = Vec::new();
+            optional_tail(reason_code, properties, &mut body);
+            packet(0xE0, &body)
+        }
+
+        pub fn auth(reason_code: ReasonCode, properties: &Properties) -> Bytes {
+            let mut body = Vec::new();
+            optional_tail(reason_code, properties, &mut body);
+            packet(0xF0, &body)
+        }
+    }
+
+    /* Tests */
+    #[cfg(test)]
+    mod tests {
+        use bytes::Bytes;
+        use mqtt::reader::*;
+        use mqtt::writer::*;
+
+        #[test]
+        fn writes_vlq() {
+            assert_eq!(vlq(0), vec![0x00]);
+            assert_eq!(vlq(127), vec![0x7F]);
+            assert_eq!(vlq(128), vec![0x80, 0x01]);
+            assert_eq!(vlq(16383), vec![0xFF, 0x7F]);
+            assert_eq!(vlq(268435455), vec![0xFF, 0xFF, 0xFF, 0x7F]);
+        }
+
+        #[test]
+        fn writes_connack_packet() {
+            let data = connack(true, ConnAckReturnCode::BadAuth);
+            assert_eq!(data, Bytes::from(vec![0x20, 0x02, 0x01, 0x04]));
+            match read_packet(data).unwrap().var_header {
+                VariableHeader::ConnAck(h) => {
+                    assert_eq!(h.session_present(), true);
+                    assert_eq!(h.return_code, ConnAckReturnCode::BadAuth);
+                }
+                _ => panic!(),
+            }
+        }
+
+        #[test]
+        fn writes_publish_packet() {
+            let data = publish("a/b", 10, &QoS::AtLeastOnce, true, false, b"Hello");
+            let packet = read_packet(data).unwrap();
+            assert_eq!(packet.header.qos, QoS::AtLeastOnce);
+            assert_eq!(packet.header.retain, true);
+            assert_eq!(packet.header.dup, false);
+            match packet.var_header {
+                VariableHeader::Publish(h) => {
+                    assert_eq!(h.topic_name, "a/b");
+                    assert_eq!(h.packet_id, 10);
+                }
+                _ => panic!(),
+            }
+            assert_eq!(packet.payload, Bytes::from(&b"Hello"[..]));
+        }
+
+        #[test]
+        fn writes_suback_packet() {
+            let codes = vec![SubAckReturnCode::MaximumQoS1, SubAckReturnCode::Failure];
+            let data = suback(1, codes.clone());
+            assert_eq!(data, Bytes::from(vec![0x90, 0x04, 0x00, 0x01, 0x01, 0x80]));
+            let payload = read_packet(data).unwrap().get_suback_payload().unwrap();
+            assert_eq!(payload.return_codes, codes);
+        }
+
+        #[test]
+        fn writes_v5_packets() {
+            let properties = Properties {
+                reason_string: Some("no".to_string()),
+                ..Properties::default()
+            };
+            let data = v5::ack(&PacketType::PubAck, 1, ReasonCode::NotAuthorized, &properties);
+            let packet = read_packet_with_version(data, ProtocolVersion::V5).unwrap();
+            assert_eq!(packet.reason_code, Some(ReasonCode::NotAuthorized));
+            assert_eq!(packet.properties, properties);
+
+            let data = v5::ack(&PacketType::PubComp, 1, ReasonCode::Success, &Properties::default());
+            assert_eq!(data, Bytes::from(vec![0x70, 0x02, 0x00, 0x01]));
+            assert_eq!(v5::disconnect(ReasonCode::Success, &Properties::default()), Bytes::from(vec![0xE0, 0x00]));
+
+            let properties = Properties {
+                message_expiry_interval: Some(10),
+                ..Properties::default()
+            };
+            let data = v5::publish("a", 2, &QoS::AtLeastOnce, false, false, &properties, b"Hi");
+            let packet = read_packet_with_version(data, ProtocolVersion::V5).unwrap();
+            assert_eq!(packet.var_header, VariableHeader::Publish(PublishHeader {
+                topic_name: "a".to_string(),
+                packet_id: 2,
+            }));
+            assert_eq!(packet.properties, properties);
+            assert_eq!(packet.payload, Bytes::from(&b"Hi"[..]));
+
+            let data = v5::suback(3, vec![ReasonCode::GrantedQoS1], &Properties::default());
+            assert_eq!(data, Bytes::from(vec![0x90, 0x04, 0x00, 0x03, 0x00, 0x01]));
+        }
+
+        #[test]
+        fn writes_acknowledgements() {
+            assert_eq!(puback(258), Bytes::from(vec![0x40, 0x02, 0x01, 0x02]));
+            assert_eq!(pubrel(1), Bytes::from(vec![0x62, 0x02, 0x00, 0x01]));
+            assert_eq!(read_packet(pubrel(1)).unwrap().var_header, VariableHeader::WithPacketId(1));
+        }
     }
 }
@@ -0,0 +1,331 @@
+use bytes::Bytes;
+use std::str::from_utf8;
+
+use topic;
+
+/* MQTT 5 properties, one field per property identifier */
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Properties {
+    pub payload_format_indicator: Option<u8>,
+    pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Bytes>,
+    /// The only property besides user properties which may repeat (in PUBLISH)
+    pub subscription_identifiers: Vec<u32>,
+    pub session_expiry_interval: Option<u32>,
+    pub assigned_client_identifier: Option<String>,
+    pub server_keep_alive: Option<u16>,
+    pub authentication_method: Option<String>,
+    pub authentication_data: Option<Bytes>,
+    pub request_problem_information: Option<u8>,
+    pub will_delay_interval: Option<u32>,
+    pub request_response_information: Option<u8>,
+    pub response_information: Option<String>,
+    pub server_reference: Option<String>,
+    pub reason_string: Option<String>,
+    pub receive_maximum: Option<u16>,
+    pub topic_alias_maximum: Option<u16>,
+    pub topic_alias: Option<u16>,
+    pub maximum_qos: Option<u8>,
+    pub retain_available: Option<u8>,
+    pub user_properties: Vec<(String, String)>,
+    pub maximum_packet_size: Option<u32>,
+    pub wildcard_subscription_available: Option<u8>,
+    pub subscription_identifier_available: Option<u8>,
+    pub shared_subscription_available: Option<u8>,
+}
+
+impl Properties {
+    pub fn is_empty(&self) -> bool {
+        *self == Properties::default()
+    }
+
+    /// What a PUBLISH carries from its sender to the subscribers
+    pub fn forwarded(&self) -> Properties {
+        Properties {
+            payload_format_indicator: self.payload_format_indicator,
+            message_expiry_interval: self.message_expiry_interval,
+            content_type: self.content_type.clone(),
+            response_topic: self.response_topic.clone(),
+            correlation_data: self.correlation_data.clone(),
+            user_properties: self.user_properties.clone(),
+            ..Properties::default()
+        }
+    }
+}
+
+/* Reading */
+fn take(bytes: &mut Bytes, count: usize) -> Result<Bytes, &'static str> {
+    if bytes.len() < count {
+        return Err("Property exceeds the property length");
+    }
+    let result = bytes.slice_to(count);
+    bytes.advance(count);
+    Ok(result)
+}
+
+fn read_u8(bytes: &mut Bytes) -> Result<u8, &'static str> {
+    Ok(take(bytes, 1)?[0])
+}
+
+fn read_u16(bytes: &mut Bytes) -> Result<u16, &'static str> {
+    let b = take(bytes, 2)?;
+    Ok((b[0] as u16) << 8 | b[1] as u16)
+}
+
+fn read_u32(bytes: &mut Bytes) -> Result<u32, &'static str> {
+    let b = take(bytes, 4)?;
+    Ok((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+}
+
+fn read_binary(bytes: &mut Bytes) -> Result<Bytes, &'static str> {
+    let len = read_u16(bytes)? as usize;
+    take(bytes, len)
+}
+
+fn read_string(bytes: &mut Bytes) -> Result<String, &'static str> {
+    let data = read_binary(bytes)?;
+    match from_utf8(&data) {
+        Ok(s) if !s.contains('\u{0}') => Ok(s.to_string()),
+        _ => Err("Invalid UTF-8 string in property"),
+    }
+}
+
+/// Variable byte integer, as used for lengths and subscription identifiers
+pub fn read_vbi(bytes: &mut Bytes) -> Result<u32, &'static str> {
+    let mut value = 0u32;
+    for i in 0..4 {
+        let byte = read_u8(bytes).map_err(|_| "Malformed variable byte integer")?;
+        value |= (byte as u32 & 127) << (7 * i);
+        if byte < 128 {
+            return Ok(value);
+        }
+    }
+    Err("Malformed variable byte integer")
+}
+
+fn set<T>(field: &mut Option<T>, value: T) -> Result<(), &'static str> {
+    if field.is_some() {
+        return Err("Property included more than once");
+    }
+    *field = Some(value);
+    Ok(())
+}
+
+fn boolean(value: u8) -> Result<u8, &'static str> {
+    match value {
+        0 | 1 => Ok(value),
+        _ => Err("Property value must be 0 or 1"),
+    }
+}
+
+fn non_zero<T: PartialEq + Default>(value: T) -> Result<T, &'static str> {
+    if value == T::default() {
+        return Err("Property value must not be 0");
+    }
+    Ok(value)
+}
+
+/// Response topics are topic names, so without wildcards
+fn topic_name(value: String) -> Result<String, &'static str> {
+    topic::validate_name(&value)?;
+    Ok(value)
+}
+
+/// Reads a length-prefixed property section, advancing past it
+pub fn read_properties(bytes: &mut Bytes) -> Result<Properties, &'static str> {
+    let length = read_vbi(bytes)? as usize;
+    let mut data = take(bytes, length).map_err(|_| "Property length exceeds the packet")?;
+    let mut p = Properties::default();
+    while !data.is_empty() {
+        let id = read_vbi(&mut data)?;
+        let data = &mut data;
+        match id {
+            0x01 => set(&mut p.payload_format_indicator, boolean(read_u8(data)?)?)?,
+            0x02 => set(&mut p.message_expiry_interval, read_u32(data)?)?,
+            0x03 => set(&mut p.content_type, read_string(data)?)?,
+            0x08 => set(&mut p.response_topic, topic_name(read_string(data)?)?)?,
+            0x09 => set(&mut p.correlation_data, read_binary(data)?)?,
+            0x0B => {
+                let id = non_zero(read_vbi(data)?)?;
+                p.subscription_identifiers.push(id);
+            }
+            0x11 => set(&mut p.session_expiry_interval, read_u32(data)?)?,
+            0x12 => set(&mut p.assigned_client_identifier, read_string(data)?)?,
+            0x13 => set(&mut p.server_keep_alive, read_u16(data)?)?,
+            0x15 => set(&mut p.authentication_method, read_string(data)?)?,
+            0x16 => set(&mut p.authentication_data, read_binary(data)?)?,
+            0x17 => set(&mut p.request_problem_information, boolean(read_u8(data)?)?)?,
+            0x18 => set(&mut p.will_delay_interval, read_u32(data)?)?,
+            0x19 => set(&mut p.request_response_information, boolean(read_u8(data)?)?)?,
+            0x1A => set(&mut p.response_information, read_string(data)?)?,
+            0x1C => set(&mut p.server_reference, read_string(data)?)?,
+            0x1F => set(&mut p.reason_string, read_string(data)?)?,
+            0x21 => set(&mut p.receive_maximum, non_zero(read_u16(data)?)?)?,
+            0x22 => set(&mut p.topic_alias_maximum, read_u16(data)?)?,
+            0x23 => set(&mut p.topic_alias, read_u16(data)?)?,
+            0x24 => set(&mut p.maximum_qos, boolean(read_u8(data)?)?)?,
+            0x25 => set(&mut p.retain_available, boolean(read_u8(data)?)?)?,
+            0x26 => {
+                let key = read_string(data)?;
+                let value = read_string(data)?;
+                p.user_properties.push((key, value));
+            }
+            0x27 => set(&mut p.maximum_packet_size, non_zero(read_u32(data)?)?)?,
+            0x28 => set(&mut p.wildcard_subscription_available, boolean(read_u8(data)?)?)?,
+            0x29 => set(&mut p.subscription_identifier_available, boolean(read_u8(data)?)?)?,
+            0x2A => set(&mut p.shared_subscription_available, boolean(read_u8(data)?)?)?,
+            _ => return Err("Unknown property identifier"),
+        }
+    }
+    Ok(p)
+}
+
+/* Writing */
+pub fn write_vbi(mut value: u32, out: &mut Vec<u8>) {
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if value == 0 {
+            return;
+        }
+    }
+}
+
+fn write_u16(value: u16, out: &mut Vec<u8>) {
+    out.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+}
+
+fn write_binary(value: &[u8], out: &mut Vec<u8>) {
+    write_u16(value.len() as u16, out);
+    out.extend_from_slice(value);
+}
+
+fn byte_property(id: u8, value: Option<u8>, out: &mut Vec<u8>) {
+    if let Some(value) = value {
+        out.extend_from_slice(&[id, value]);
+    }
+}
+
+fn u16_property(id: u8, value: Option<u16>, out: &mut Vec<u8>) {
+    if let Some(value) = value {
+        out.push(id);
+        write_u16(value, out);
+    }
+}
+
+fn u32_property(id: u8, value: Option<u32>, out: &mut Vec<u8>) {
+    if let Some(value) = value {
+        out.extend_from_slice(&[id, (value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+    }
+}
+
+fn binary_property(id: u8, value: Option<&[u8]>, out: &mut Vec<u8>) {
+    if let Some(value) = value {
+        out.push(id);
+        write_binary(value, out);
+    }
+}
+
+fn string_property(id: u8, value: &Option<String>, out: &mut Vec<u8>) {
+    binary_property(id, value.as_ref().map(|s| s.as_bytes()), out);
+}
+
+/// Appends the property section, length prefix included
+pub fn write_properties(p: &Properties, out: &mut Vec<u8>) {
+    let mut data = Vec::new();
+    {
+        let d = &mut data;
+        byte_property(0x01, p.payload_format_indicator, d);
+        u32_property(0x02, p.message_expiry_interval, d);
+        string_property(0x03, &p.content_type, d);
+        string_property(0x08, &p.response_topic, d);
+        binary_property(0x09, p.correlation_data.as_ref().map(|b| &b[..]), d);
+        for id in p.subscription_identifiers.iter() {
+            d.push(0x0B);
+            write_vbi(*id, d);
+        }
+        u32_property(0x11, p.session_expiry_interval, d);
+        string_property(0x12, &p.assigned_client_identifier, d);
+        u16_property(0x13, p.server_keep_alive, d);
+        string_property(0x15, &p.authentication_method, d);
+        binary_property(0x16, p.authentication_data.as_ref().map(|b| &b[..]), d);
+        byte_property(0x17, p.request_problem_information, d);
+        u32_property(0x18, p.will_delay_interval, d);
+        byte_property(0x19, p.request_response_information, d);
+        string_property(0x1A, &p.response_information, d);
+        string_property(0x1C, &p.server_reference, d);
+        string_property(0x1F, &p.reason_string, d);
+        u16_property(0x21, p.receive_maximum, d);
+        u16_property(0x22, p.topic_alias_maximum, d);
+        u16_property(0x23, p.topic_alias, d);
+        byte_property(0x24, p.maximum_qos, d);
+        byte_property(0x25, p.retain_available, d);
+        for (key, value) in p.user_properties.iter() {
+            d.push(0x26);
+            write_binary(key.as_bytes(), d);
+            write_binary(value.as_bytes(), d);
+        }
+        u32_property(0x27, p.maximum_packet_size, d);
+        byte_property(0x28, p.wildcard_subscription_available, d);
+        byte_property(0x29, p.subscription_identifier_available, d);
+        byte_property(0x2A, p.shared_subscription_available, d);
+    }
+    write_vbi(data.len() as u32, out);
+    out.extend(data);
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use mqtt::properties::*;
+
+    #[test]
+    fn round_trips_properties() {
+        let properties = Properties {
+            message_expiry_interval: Some(120),
+            response_topic: Some("replies/1".to_string()),
+            correlation_data: Some(Bytes::from(vec![0xFF, 0x00])),
+            subscription_identifiers: vec![1, 268435455],
+            receive_maximum: Some(10),
+            user_properties: vec![
+                ("a".to_string(), "1".to_string()),
+                ("a".to_string(), "2".to_string()),
+            ],
+            ..Properties::default()
+        };
+        let mut data = Vec::new();
+        write_properties(&properties, &mut data);
+        data.push(0x42);
+        let mut bytes = Bytes::from(data);
+        assert_eq!(read_properties(&mut bytes).unwrap(), properties);
+        assert_eq!(bytes, Bytes::from(vec![0x42]));
+    }
+
+    #[test]
+    fn writes_empty_properties_as_zero_length() {
+        let mut data = Vec::new();
+        write_properties(&Properties::default(), &mut data);
+        assert_eq!(data, vec![0x00]);
+    }
+
+    #[test]
+    fn rejects_invalid_properties() {
+        let read = |data: Vec<u8>| read_properties(&mut Bytes::from(data));
+        assert_eq!(read(vec![0x02, 0x21, 0x00]), Err("Property exceeds the property length"));
+        assert_eq!(read(vec![0x05, 0x02, 0x00, 0x00]), Err("Property length exceeds the packet"));
+        assert_eq!(read(vec![0x04, 0x01, 0x01, 0x01, 0x00]), Err("Property included more than once"));
+        assert_eq!(read(vec![0x02, 0x01, 0x02]), Err("Property value must be 0 or 1"));
+        assert_eq!(read(vec![0x03, 0x21, 0x00, 0x00]), Err("Property value must not be 0"));
+        assert_eq!(read(vec![0x01, 0x7F]), Err("Unknown property identifier"));
+        // Response topic "a/#"
+        let data = vec![0x06, 0x08, 0x00, 0x03, 0x61, 0x2F, 0x23];
+        assert_eq!(read(data), Err("Wildcards are not allowed in topic names"));
+    }
+}
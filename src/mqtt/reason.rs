@@ -0,0 +1,207 @@
+use mqtt::{ConnAckReturnCode, QoS};
+
+/* MQTT 5 reason codes, shared by CONNACK, the acks, DISCONNECT and AUTH */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReasonCode {
+    /// Also "Normal disconnection" and "Granted QoS 0"
+    Success,
+    GrantedQoS1,
+    GrantedQoS2,
+    DisconnectWithWill,
+    NoMatchingSubscribers,
+    NoSubscriptionExisted,
+    ContinueAuthentication,
+    ReAuthenticate,
+    UnspecifiedError,
+    MalformedPacket,
+    ProtocolError,
+    ImplementationSpecificError,
+    UnsupportedProtocolVersion,
+    ClientIdentifierNotValid,
+    BadUserNameOrPassword,
+    NotAuthorized,
+    ServerUnavailable,
+    ServerBusy,
+    Banned,
+    ServerShuttingDown,
+    BadAuthenticationMethod,
+    KeepAliveTimeout,
+    SessionTakenOver,
+    TopicFilterInvalid,
+    TopicNameInvalid,
+    PacketIdentifierInUse,
+    PacketIdentifierNotFound,
+    ReceiveMaximumExceeded,
+    TopicAliasInvalid,
+    PacketTooLarge,
+    MessageRateTooHigh,
+    QuotaExceeded,
+    AdministrativeAction,
+    PayloadFormatInvalid,
+    RetainNotSupported,
+    QoSNotSupported,
+    UseAnotherServer,
+    ServerMoved,
+    SharedSubscriptionsNotSupported,
+    ConnectionRateExceeded,
+    MaximumConnectTime,
+    SubscriptionIdentifiersNotSupported,
+    WildcardSubscriptionsNotSupported,
+}
+
+impl ReasonCode {
+    pub fn from_byte(value: u8) -> Result<ReasonCode, &'static str> {
+        Ok(match value {
+            0x00 => ReasonCode::Success,
+            0x01 => ReasonCode::GrantedQoS1,
+            0x02 => ReasonCode::GrantedQoS2,
+            0x04 => ReasonCode::DisconnectWithWill,
+            0x10 => ReasonCode::NoMatchingSubscribers,
+            0x11 => ReasonCode::NoSubscriptionExisted,
+            0x18 => ReasonCode::ContinueAuthentication,
+            0x19 => ReasonCode::ReAuthenticate,
+            0x80 => ReasonCode::UnspecifiedError,
+            0x81 => ReasonCode::MalformedPacket,
+            0x82 => ReasonCode::ProtocolError,
+            0x83 => ReasonCode::ImplementationSpecificError,
+            0x84 => ReasonCode::UnsupportedProtocolVersion,
+            0x85 => ReasonCode::ClientIdentifierNotValid,
+            0x86 => ReasonCode::BadUserNameOrPassword,
+            0x87 => ReasonCode::NotAuthorized,
+            0x88 => ReasonCode::ServerUnavailable,
+            0x89 => ReasonCode::ServerBusy,
+            0x8A => ReasonCode::Banned,
+            0x8B => ReasonCode::ServerShuttingDown,
+            0x8C => ReasonCode::BadAuthenticationMethod,
+            0x8D => ReasonCode::KeepAliveTimeout,
+            0x8E => ReasonCode::SessionTakenOver,
+            0x8F => ReasonCode::TopicFilterInvalid,
+            0x90 => ReasonCode::TopicNameInvalid,
+            0x91 => ReasonCode::PacketIdentifierInUse,
+            0x92 => ReasonCode::PacketIdentifierNotFound,
+            0x93 => ReasonCode::ReceiveMaximumExceeded,
+            0x94 => ReasonCode::TopicAliasInvalid,
+            0x95 => ReasonCode::PacketTooLarge,
+            0x96 => ReasonCode::MessageRateTooHigh,
+            0x97 => ReasonCode::QuotaExceeded,
+            0x98 => ReasonCode::AdministrativeAction,
+            0x99 => ReasonCode::PayloadFormatInvalid,
+            0x9A => ReasonCode::RetainNotSupported,
+            0x9B => ReasonCode::QoSNotSupported,
+            0x9C => ReasonCode::UseAnotherServer,
+            0x9D => ReasonCode::ServerMoved,
+            0x9E => ReasonCode::SharedSubscriptionsNotSupported,
+            0x9F => ReasonCode::ConnectionRateExceeded,
+            0xA0 => ReasonCode::MaximumConnectTime,
+            0xA1 => ReasonCode::SubscriptionIdentifiersNotSupported,
+            0xA2 => ReasonCode::WildcardSubscriptionsNotSupported,
+            _ => return Err("Unknown reason code"),
+        })
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            ReasonCode::Success => 0x00,
+            ReasonCode::GrantedQoS1 => 0x01,
+            ReasonCode::GrantedQoS2 => 0x02,
+            ReasonCode::DisconnectWithWill => 0x04,
+            ReasonCode::NoMatchingSubscribers => 0x10,
+            ReasonCode::NoSubscriptionExisted => 0x11,
+            ReasonCode::ContinueAuthentication => 0x18,
+            ReasonCode::ReAuthenticate => 0x19,
+            ReasonCode::UnspecifiedError => 0x80,
+            ReasonCode::MalformedPacket => 0x81,
+            ReasonCode::ProtocolError => 0x82,
+            ReasonCode::ImplementationSpecificError => 0x83,
+            ReasonCode::UnsupportedProtocolVersion => 0x84,
+            ReasonCode::ClientIdentifierNotValid => 0x85,
+            ReasonCode::BadUserNameOrPassword => 0x86,
+            ReasonCode::NotAuthorized => 0x87,
+            ReasonCode::ServerUnavailable => 0x88,
+            ReasonCode::ServerBusy => 0x89,
+            ReasonCode::Banned => 0x8A,
+            ReasonCode::ServerShuttingDown => 0x8B,
+            ReasonCode::BadAuthenticationMethod => 0x8C,
+            ReasonCode::KeepAliveTimeout => 0x8D,
+            ReasonCode::SessionTakenOver => 0x8E,
+            ReasonCode::TopicFilterInvalid => 0x8F,
+            ReasonCode::TopicNameInvalid => 0x90,
+            ReasonCode::PacketIdentifierInUse => 0x91,
+            ReasonCode::PacketIdentifierNotFound => 0x92,
+            ReasonCode::ReceiveMaximumExceeded => 0x93,
+            ReasonCode::TopicAliasInvalid => 0x94,
+            ReasonCode::PacketTooLarge => 0x95,
+            ReasonCode::MessageRateTooHigh => 0x96,
+            ReasonCode::QuotaExceeded => 0x97,
+            ReasonCode::AdministrativeAction => 0x98,
+            ReasonCode::PayloadFormatInvalid => 0x99,
+            ReasonCode::RetainNotSupported => 0x9A,
+            ReasonCode::QoSNotSupported => 0x9B,
+            ReasonCode::UseAnotherServer => 0x9C,
+            ReasonCode::ServerMoved => 0x9D,
+            ReasonCode::SharedSubscriptionsNotSupported => 0x9E,
+            ReasonCode::ConnectionRateExceeded => 0x9F,
+            ReasonCode::MaximumConnectTime => 0xA0,
+            ReasonCode::SubscriptionIdentifiersNotSupported => 0xA1,
+            ReasonCode::WildcardSubscriptionsNotSupported => 0xA2,
+        }
+    }
+
+    /// SUBACK code for a granted subscription
+    pub fn granted(qos: &QoS) -> ReasonCode {
+        match *qos {
+            QoS::AtMostOnce => ReasonCode::Success,
+            QoS::AtLeastOnce => ReasonCode::GrantedQoS1,
+            QoS::ExactlyOnce => ReasonCode::GrantedQoS2,
+            QoS::Reserved => ReasonCode::UnspecifiedError,
+        }
+    }
+
+    pub fn is_error(self) -> bool {
+        self.to_byte() >= 0x80
+    }
+
+    /// Closest MQTT 3.1.1 CONNACK return code for refusing a connection
+    pub fn to_connack_return_code(self) -> ConnAckReturnCode {
+        match self {
+            ReasonCode::Success => ConnAckReturnCode::Accepted,
+            ReasonCode::UnsupportedProtocolVersion => ConnAckReturnCode::UnacceptableProtocol,
+            ReasonCode::ClientIdentifierNotValid => ConnAckReturnCode::IdentifierRejected,
+            ReasonCode::BadUserNameOrPassword | ReasonCode::BadAuthenticationMethod => {
+                ConnAckReturnCode::BadAuth
+            }
+            ReasonCode::NotAuthorized | ReasonCode::Banned => ConnAckReturnCode::NotAuthorized,
+            _ => ConnAckReturnCode::ServerUnavailable,
+        }
+    }
+
+    pub fn from_connack_return_code(code: ConnAckReturnCode) -> ReasonCode {
+        match code {
+            ConnAckReturnCode::Accepted => ReasonCode::Success,
+            ConnAckReturnCode::UnacceptableProtocol => ReasonCode::UnsupportedProtocolVersion,
+            ConnAckReturnCode::IdentifierRejected => ReasonCode::ClientIdentifierNotValid,
+            ConnAckReturnCode::ServerUnavailable => ReasonCode::ServerUnavailable,
+            ConnAckReturnCode::BadAuth => ReasonCode::BadUserNameOrPassword,
+            ConnAckReturnCode::NotAuthorized => ReasonCode::NotAuthorized,
+            ConnAckReturnCode::Reserved => ReasonCode::UnspecifiedError,
+        }
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use mqtt::*;
+
+    #[test]
+    fn round_trips_reason_codes() {
+        for byte in 0..=255u8 {
+            if let Ok(code) = ReasonCode::from_byte(byte) {
+                assert_eq!(code.to_byte(), byte);
+            }
+        }
+        assert_eq!(ReasonCode::from_byte(0x03), Err("Unknown reason code"));
+        assert!(ReasonCode::NotAuthorized.is_error());
+        assert!(!ReasonCode::NoMatchingSubscribers.is_error());
+    }
+}
@@ -0,0 +1,404 @@
+extern crate base64;
+extern crate hmac;
+extern crate pbkdf2;
+extern crate rand;
+extern crate sha2;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+/* Password file: one `username:$pbkdf2-sha256$iterations$salt$hash` or
+ * `username:$scram-sha-256$iterations$salt$stored_key$server_key` entry per line
+ */
+pub const DEFAULT_ITERATIONS: u32 = 100000;
+const SCHEME: &str = "pbkdf2-sha256";
+const SCRAM_SCHEME: &str = "scram-sha-256";
+pub const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PasswordHash {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+/// Credentials of one password file entry
+#[derive(Debug, PartialEq, Clone)]
+pub enum Credentials {
+    Pbkdf2(PasswordHash),
+    Scram(ScramCredentials),
+}
+
+/// What RFC 5802 has servers store for SCRAM-SHA-256: enough to check a proof
+/// or a password, but not to compute a proof without the password
+#[derive(Debug, PartialEq, Clone)]
+pub struct ScramCredentials {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+fn derive(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut hash = vec![0u8; HASH_LENGTH];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password, salt, iterations as usize, &mut hash);
+    hash
+}
+
+fn random_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; SALT_LENGTH];
+    rand::thread_rng().fill(&mut salt[..]);
+    salt
+}
+
+pub fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+/// Compares without returning early, so timing does not leak the matching prefix
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl PasswordHash {
+    /// Hashes `password` with a fresh random salt
+    pub fn new(password: &[u8], iterations: u32) -> PasswordHash {
+        let salt = random_salt();
+        let hash = derive(password, &salt, iterations);
+        PasswordHash {
+            iterations: iterations,
+            salt: salt,
+            hash: hash,
+        }
+    }
+
+    pub fn verify(&self, password: &[u8]) -> bool {
+        constant_time_eq(&derive(password, &self.salt, self.iterations), &self.hash)
+    }
+}
+
+impl FromStr for PasswordHash {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<PasswordHash, &'static str> {
+        let parts: Vec<&str> = s.split('$').collect();
+        if parts.len() != 5 || !parts[0].is_empty() {
+            return Err("Malformed password hash");
+        }
+        if parts[1] != SCHEME {
+            return Err("Unsupported password hash scheme");
+        }
+        let iterations = match u32::from_str(parts[2]) {
+            Ok(i) if i > 0 => i,
+            _ => return Err("Invalid iteration count"),
+        };
+        match (base64::decode(parts[3]), base64::decode(parts[4])) {
+            (Ok(salt), Ok(hash)) => Ok(PasswordHash {
+                iterations: iterations,
+                salt: salt,
+                hash: hash,
+            }),
+            _ => Err("Invalid base64 in password hash"),
+        }
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "${}${}${}${}",
+            SCHEME,
+            self.iterations,
+            base64::encode(&self.salt),
+            base64::encode(&self.hash)
+        )
+    }
+}
+
+impl ScramCredentials {
+    /// Derives the keys from `password` with a fresh random salt
+    pub fn new(password: &[u8], iterations: u32) -> ScramCredentials {
+        let salt = random_salt();
+        let salted_password = derive(password, &salt, iterations);
+        ScramCredentials {
+            iterations: iterations,
+            salt: salt,
+            stored_key: Sha256::digest(&hmac(&salted_password, b"Client Key")).to_vec(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    pub fn verify(&self, password: &[u8]) -> bool {
+        let client_key = hmac(&derive(password, &self.salt, self.iterations), b"Client Key");
+        constant_time_eq(&Sha256::digest(&client_key), &self.stored_key)
+    }
+}
+
+impl Credentials {
+    pub fn verify(&self, password: &[u8]) -> bool {
+        match *self {
+            Credentials::Pbkdf2(ref hash) => hash.verify(password),
+            Credentials::Scram(ref credentials) => credentials.verify(password),
+        }
+    }
+
+    /// Only entries stored as SCRAM credentials can be used for SCRAM-SHA-256
+    pub fn scram(&self) -> Option<&ScramCredentials> {
+        match *self {
+            Credentials::Pbkdf2(_) => None,
+            Credentials::Scram(ref credentials) => Some(credentials),
+        }
+    }
+}
+
+impl FromStr for Credentials {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Credentials, &'static str> {
+        let parts: Vec<&str> = s.split('$').collect();
+        if parts.len() < 2 || !parts[0].is_empty() {
+            return Err("Malformed password hash");
+        }
+        if parts[1] == SCHEME {
+            return PasswordHash::from_str(s).map(Credentials::Pbkdf2);
+        }
+        if parts[1] != SCRAM_SCHEME {
+            return Err("Unsupported password hash scheme");
+        }
+        if parts.len() != 6 {
+            return Err("Malformed SCRAM credentials");
+        }
+        let iterations = match u32::from_str(parts[2]) {
+            Ok(i) if i > 0 => i,
+            _ => return Err("Invalid iteration count"),
+        };
+        let keys = (base64::decode(parts[3]), base64::decode(parts[4]), base64::decode(parts[5]));
+        match keys {
+            (Ok(salt), Ok(stored_key), Ok(server_key)) => Ok(Credentials::Scram(ScramCredentials {
+                iterations: iterations,
+                salt: salt,
+                stored_key: stored_key,
+                server_key: server_key,
+            })),
+            _ => Err("Invalid base64 in SCRAM credentials"),
+        }
+    }
+}
+
+impl fmt::Display for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Credentials::Pbkdf2(ref hash) => fmt::Display::fmt(hash, f),
+            Credentials::Scram(ref credentials) => write!(
+                f,
+                "${}${}${}${}${}",
+                SCRAM_SCHEME,
+                credentials.iterations,
+                base64::encode(&credentials.salt),
+                base64::encode(&credentials.stored_key),
+                base64::encode(&credentials.server_key)
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PasswordFile {
+    path: PathBuf,
+    users: BTreeMap<String, Credentials>,
+}
+
+impl PasswordFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> PasswordFile {
+        PasswordFile {
+            path: path.as_ref().to_path_buf(),
+            users: BTreeMap::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<PasswordFile, String> {
+        let mut file = PasswordFile::new(path);
+        file.reload()?;
+        Ok(file)
+    }
+
+    /// Re-reads the file, keeping the current users if it cannot be parsed
+    pub fn reload(&mut self) -> Result<(), String> {
+        let mut contents = String::new();
+        File::open(&self.path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        self.users = PasswordFile::parse(&contents)
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        Ok(())
+    }
+
+    pub fn parse(contents: &str) -> Result<BTreeMap<String, Credentials>, String> {
+        let mut users = BTreeMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut entry = line.splitn(2, ':');
+            let (username, hash) = match (entry.next(), entry.next()) {
+                (Some(u), Some(h)) if !u.is_empty() => (u, h),
+                _ => return Err(format!("line {}: expected `username:hash`", i + 1)),
+            };
+            let credentials = Credentials::from_str(hash)
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            users.insert(username.to_string(), credentials);
+        }
+        Ok(users)
+    }
+
+    /// Writes a temporary file next to the original and renames it over it,
+    /// so a reload never reads a half-written file
+    pub fn save(&self) -> io::Result<()> {
+        let mut name = self.path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+        name.push(format!(".{}.tmp", process::id()));
+        let temporary = self.path.with_file_name(name);
+        let result = self.write_to(&temporary).and_then(|_| fs::rename(&temporary, &self.path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary);
+        }
+        result
+    }
+
+    fn write_to(&self, path: &Path) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        // Hashes should stay as private as the file they replace
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        for (username, credentials) in self.users.iter() {
+            writeln!(file, "{}:{}", username, credentials)?;
+        }
+        file.sync_all()
+    }
+
+    pub fn verify(&self, username: &str, password: &[u8]) -> bool {
+        match self.users.get(username) {
+            Some(credentials) => credentials.verify(password),
+            None => false,
+        }
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Credentials> {
+        self.users.get(username)
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.users.contains_key(username)
+    }
+
+    pub fn set(&mut self, username: &str, credentials: Credentials) {
+        self.users.insert(username.to_string(), credentials);
+    }
+
+    pub fn remove(&mut self, username: &str) -> bool {
+        self.users.remove(username).is_some()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use passwd::*;
+
+    #[test]
+    fn verifies_hashed_password() {
+        let hash = PasswordHash::new(b"secret", 10);
+        assert!(hash.verify(b"secret"));
+        assert!(!hash.verify(b"Secret"));
+        assert!(!hash.verify(b""));
+    }
+
+    #[test]
+    fn round_trips_hash_string() {
+        let hash = PasswordHash::new(b"secret", 10);
+        let parsed = PasswordHash::from_str(&hash.to_string()).unwrap();
+        assert_eq!(parsed, hash);
+        assert!(parsed.verify(b"secret"));
+    }
+
+    #[test]
+    fn verifies_passwords_against_scram_credentials() {
+        let credentials = Credentials::Scram(ScramCredentials::new(b"secret", 10));
+        assert!(credentials.verify(b"secret"));
+        assert!(!credentials.verify(b"Secret"));
+        let parsed = Credentials::from_str(&credentials.to_string()).unwrap();
+        assert_eq!(parsed, credentials);
+        assert!(parsed.to_string().starts_with("$scram-sha-256$10$"));
+        assert!(parsed.scram().is_some());
+        assert!(Credentials::Pbkdf2(PasswordHash::new(b"secret", 10)).scram().is_none());
+    }
+
+    #[test]
+    fn parses_password_file() {
+        let hash = PasswordHash::new(b"secret", 10);
+        let scram = ScramCredentials::new(b"other", 10);
+        let contents = format!(
+            "# devices\n\nsensor-1:{}\nsensor-2:{}\n",
+            hash,
+            Credentials::Scram(scram)
+        );
+        let users = PasswordFile::parse(&contents).unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(users["sensor-1"].verify(b"secret"));
+        assert!(users["sensor-2"].verify(b"other"));
+    }
+
+    #[test]
+    fn saves_by_replacing_the_file() {
+        use std::env;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("picomq-passwd-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("passwd");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let mut passwords = PasswordFile::load(&path).unwrap();
+        passwords.set("alice", Credentials::Pbkdf2(PasswordHash::new(b"secret", 10)));
+        passwords.save().unwrap();
+
+        let saved = PasswordFile::load(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let files = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(saved.verify("alice", b"secret"));
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let err = PasswordFile::parse("alice:$pbkdf2-sha256$10$c2FsdA==$aGFzaA==\nbob\n");
+        assert_eq!(err.unwrap_err(), "line 2: expected `username:hash`");
+        let err = PasswordFile::parse("alice:$md5$10$c2FsdA==$aGFzaA==\n");
+        assert_eq!(err.unwrap_err(), "line 1: Unsupported password hash scheme");
+        let err = PasswordFile::parse("alice:$scram-sha-256$10$c2FsdA==$a2V5\n");
+        assert_eq!(err.unwrap_err(), "line 1: Malformed SCRAM credentials");
+    }
+}
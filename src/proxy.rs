@@ -0,0 +1,254 @@
+extern crate futures;
+extern crate tokio_io;
+
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{from_utf8, FromStr};
+
+use futures::Future;
+use futures::future::{self, Loop};
+use tokio_io::AsyncRead;
+use tokio_io::io;
+
+/* PROXY protocol (HAProxy) header, sent by load balancers before the client's own data */
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProxyHeader {
+    pub version: u8,
+    /// Original client address, `None` for LOCAL/UNKNOWN connections
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+fn to_u16(msb: u8, lsb: u8) -> u16 {
+    lsb as u16 | (msb as u16) << 8
+}
+
+pub fn parse_v1(line: &[u8]) -> Result<ProxyHeader, &'static str> {
+    if line.len() > V1_MAX_LENGTH {
+        return Err("PROXY v1 header is too long");
+    }
+    if !line.starts_with(V1_PREFIX) || !line.ends_with(b"\r\n") {
+        return Err("Malformed PROXY v1 header");
+    }
+    let line = match from_utf8(&line[..line.len() - 2]) {
+        Ok(s) => s,
+        Err(_) => return Err("Invalid characters in PROXY v1 header"),
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields[1] == "UNKNOWN" {
+        return Ok(ProxyHeader {
+            version: 1,
+            source: None,
+            destination: None,
+        });
+    }
+    if fields.len() != 6 {
+        return Err("Wrong number of fields in PROXY v1 header");
+    }
+    let (src_ip, dst_ip) = match fields[1] {
+        "TCP4" => {
+            match (Ipv4Addr::from_str(fields[2]), Ipv4Addr::from_str(fields[3])) {
+                (Ok(s), Ok(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+                _ => return Err("Invalid IPv4 address in PROXY v1 header"),
+            }
+        }
+        "TCP6" => {
+            match (Ipv6Addr::from_str(fields[2]), Ipv6Addr::from_str(fields[3])) {
+                (Ok(s), Ok(d)) => (IpAddr::V6(s), IpAddr::V6(d)),
+                _ => return Err("Invalid IPv6 address in PROXY v1 header"),
+            }
+        }
+        _ => return Err("Unsupported protocol in PROXY v1 header"),
+    };
+    match (u16::from_str(fields[4]), u16::from_str(fields[5])) {
+        (Ok(src_port), Ok(dst_port)) => Ok(ProxyHeader {
+            version: 1,
+            source: Some(SocketAddr::new(src_ip, src_port)),
+            destination: Some(SocketAddr::new(dst_ip, dst_port)),
+        }),
+        _ => Err("Invalid port in PROXY v1 header"),
+    }
+}
+
+pub fn parse_v2(bytes: &[u8]) -> Result<ProxyHeader, &'static str> {
+    if bytes.len() < 16 || bytes[..12] != V2_SIGNATURE {
+        return Err("Malformed PROXY v2 header");
+    }
+    if bytes[12] >> 4 != 2 {
+        return Err("Unsupported PROXY protocol version");
+    }
+    let length = to_u16(bytes[14], bytes[15]) as usize;
+    if bytes.len() < length + 16 {
+        return Err("Not enough data supplied");
+    }
+    let local = ProxyHeader {
+        version: 2,
+        source: None,
+        destination: None,
+    };
+    match bytes[12] & 0x0F {
+        // LOCAL, e.g. health checks from the balancer itself
+        0 => return Ok(local),
+        1 => {}
+        _ => return Err("Invalid PROXY v2 command"),
+    }
+    let addr = &bytes[16..16 + length];
+    match bytes[13] {
+        // TCP over IPv4
+        0x11 => {
+            if addr.len() < 12 {
+                return Err("Not enough data supplied");
+            }
+            let src = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let dst = Ipv4Addr::new(addr[4], addr[5], addr[6], addr[7]);
+            Ok(ProxyHeader {
+                version: 2,
+                source: Some(SocketAddr::new(IpAddr::V4(src), to_u16(addr[8], addr[9]))),
+                destination: Some(SocketAddr::new(IpAddr::V4(dst), to_u16(addr[10], addr[11]))),
+            })
+        }
+        // TCP over IPv6
+        0x21 => {
+            if addr.len() < 36 {
+                return Err("Not enough data supplied");
+            }
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&addr[0..16]);
+            dst.copy_from_slice(&addr[16..32]);
+            Ok(ProxyHeader {
+                version: 2,
+                source: Some(SocketAddr::new(
+                    IpAddr::V6(Ipv6Addr::from(src)),
+                    to_u16(addr[32], addr[33]),
+                )),
+                destination: Some(SocketAddr::new(
+                    IpAddr::V6(Ipv6Addr::from(dst)),
+                    to_u16(addr[34], addr[35]),
+                )),
+            })
+        }
+        // UNSPEC, UNIX sockets and datagrams carry no usable client address
+        _ => Ok(local),
+    }
+}
+
+fn invalid_data(msg: &'static str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Reads a PROXY v1 or v2 header from the start of the stream, leaving
+/// the reader positioned at the first byte of the client's data.
+pub fn read_proxy_header<R>(reader: R) -> Box<dyn Future<Item = (R, ProxyHeader), Error = Error>>
+where
+    R: AsyncRead + 'static,
+{
+    // Both versions are at least as long as the v2 signature
+    let header = io::read_exact(reader, [0u8; 12]).and_then(|(reader, start)| {
+        if start == V2_SIGNATURE {
+            let fixed = io::read_exact(reader, [0u8; 4]).and_then(move |(reader, rest)| {
+                let length = to_u16(rest[2], rest[3]) as usize;
+                let mut header = start.to_vec();
+                header.extend_from_slice(&rest);
+                io::read_exact(reader, vec![0u8; length]).map(move |(reader, addr)| {
+                    header.extend_from_slice(&addr);
+                    (reader, header)
+                })
+            });
+            let header = fixed.and_then(|(reader, header)| match parse_v2(&header) {
+                Ok(h) => Ok((reader, h)),
+                Err(e) => Err(invalid_data(e)),
+            });
+            Box::new(header) as Box<dyn Future<Item = _, Error = _>>
+        } else if start.starts_with(V1_PREFIX) {
+            let line = future::loop_fn((reader, start.to_vec()), |(reader, mut line)| {
+                io::read_exact(reader, [0u8; 1]).and_then(move |(reader, byte)| {
+                    line.push(byte[0]);
+                    if line.ends_with(b"\r\n") {
+                        Ok(Loop::Break((reader, line)))
+                    } else if line.len() >= V1_MAX_LENGTH {
+                        Err(invalid_data("PROXY v1 header is too long"))
+                    } else {
+                        Ok(Loop::Continue((reader, line)))
+                    }
+                })
+            });
+            let header = line.and_then(|(reader, line)| match parse_v1(&line) {
+                Ok(h) => Ok((reader, h)),
+                Err(e) => Err(invalid_data(e)),
+            });
+            Box::new(header) as Box<dyn Future<Item = _, Error = _>>
+        } else {
+            Box::new(future::err(invalid_data("Missing PROXY protocol header")))
+        }
+    });
+    Box::new(header)
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use proxy::*;
+
+    #[test]
+    fn parses_v1_tcp4_header() {
+        let header = parse_v1(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n").unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("192.168.0.11:1883".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_v1_tcp6_header() {
+        let header = parse_v1(b"PROXY TCP6 ::1 2001:db8::1 40000 8883\r\n").unwrap();
+        assert_eq!(header.source, Some("[::1]:40000".parse().unwrap()));
+        assert_eq!(header.destination, Some("[2001:db8::1]:8883".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_v1_unknown_header() {
+        let header = parse_v1(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").unwrap();
+        assert_eq!(header.source, None);
+    }
+
+    #[test]
+    fn rejects_malformed_v1_header() {
+        assert!(parse_v1(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 ::1 ::1 1 2\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 1.1.1.1 2.2.2.2 70000 1\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 1.1.1.1 2.2.2.2 1 2").is_err());
+    }
+
+    #[test]
+    fn parses_v2_tcp4_header() {
+        let mut data = V2_SIGNATURE.to_vec();
+        // v2 PROXY, TCP over IPv4, 12 address bytes
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        data.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2, 0xDC, 0x04, 0x07, 0x5B]);
+        let header = parse_v2(&data).unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!(header.source, Some("10.0.0.1:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("10.0.0.2:1883".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_v2_local_header() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        let header = parse_v2(&data).unwrap();
+        assert_eq!(header.source, None);
+    }
+
+    #[test]
+    fn rejects_truncated_v2_header() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C, 10, 0, 0, 1]);
+        assert!(parse_v2(&data).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use std::str;
+
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use passwd::{constant_time_eq, hmac, ScramCredentials, DEFAULT_ITERATIONS, SALT_LENGTH};
+
+/* SCRAM-SHA-256 (RFC 5802, RFC 7677) server side, carried in MQTT 5 CONNECT and AUTH */
+pub const MECHANISM: &str = "SCRAM-SHA-256";
+const NONCE_LENGTH: usize = 18;
+
+thread_local! {
+    /// Key for the made-up salts of unknown users, which stay the same per user name
+    static FAKE_SALT_KEY: Vec<u8> = {
+        let mut key = vec![0u8; 32];
+        rand::thread_rng().fill(&mut key[..]);
+        key
+    };
+}
+
+/// Reads `<key>=<value>` from a message attribute
+fn attribute(part: Option<&str>, key: char) -> Result<&str, &'static str> {
+    match part {
+        Some(part) if part.starts_with(key) && part[1..].starts_with('=') => Ok(&part[2..]),
+        _ => Err("Malformed SCRAM message"),
+    }
+}
+
+/// Undoes the `=2C` and `=3D` escaping of commas and equals signs in user names
+fn decode_name(name: &str) -> Result<String, &'static str> {
+    let mut result = String::new();
+    let mut rest = name;
+    while let Some(i) = rest.find('=') {
+        result.push_str(&rest[..i]);
+        match rest.get(i..i + 3) {
+            Some("=2C") => result.push(','),
+            Some("=3D") => result.push('='),
+            _ => return Err("Malformed SCRAM user name"),
+        }
+        rest = &rest[i + 3..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// One authentication, from the client-first-message to the server-final-message
+pub struct ScramExchange {
+    pub username: String,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+    /// None for unknown users, who go through the same steps and fail at the end
+    credentials: Option<ScramCredentials>,
+}
+
+impl ScramExchange {
+    /// Reads the client-first-message, `lookup` returns the user's credentials.
+    /// Returns the exchange and the server-first-message.
+    pub fn start<F>(client_first: &[u8], lookup: F) -> Result<(ScramExchange, Vec<u8>), &'static str>
+    where
+        F: FnOnce(&str) -> Option<ScramCredentials>,
+    {
+        let client_first = str::from_utf8(client_first).map_err(|_| "Malformed SCRAM message")?;
+        let mut parts = client_first.splitn(3, ',');
+        // Channel binding is not available to MQTT over a plain stream
+        match parts.next() {
+            Some("n") | Some("y") => {}
+            _ => return Err("SCRAM channel binding is not supported"),
+        }
+        let authzid = parts.next().ok_or("Malformed SCRAM message")?;
+        if !authzid.is_empty() {
+            return Err("SCRAM authorization identities are not supported");
+        }
+        let client_first_bare = parts.next().ok_or("Malformed SCRAM message")?;
+        let gs2_header = client_first[..client_first.len() - client_first_bare.len()].to_string();
+        let mut attributes = client_first_bare.split(',');
+        let username = decode_name(attribute(attributes.next(), 'n')?)?;
+        let client_nonce = attribute(attributes.next(), 'r')?;
+        if client_nonce.is_empty() || !client_nonce.chars().all(|c| c > ' ' && c <= '~' && c != ',') {
+            return Err("Malformed SCRAM nonce");
+        }
+
+        let mut server_nonce = vec![0u8; NONCE_LENGTH];
+        rand::thread_rng().fill(&mut server_nonce[..]);
+        let nonce = format!("{}{}", client_nonce, base64::encode(&server_nonce));
+        let credentials = lookup(&username);
+        let (salt, iterations) = match credentials {
+            Some(ref credentials) => (credentials.salt.clone(), credentials.iterations),
+            // Made-up parameters, so unknown users cannot be told apart yet
+            None => {
+                let salt = FAKE_SALT_KEY.with(|key| hmac(key, username.as_bytes()));
+                (salt[..SALT_LENGTH].to_vec(), DEFAULT_ITERATIONS)
+            }
+        };
+        let server_first = format!("r={},s={},i={}", nonce, base64::encode(&salt), iterations);
+        let exchange = ScramExchange {
+            username: username,
+            gs2_header: gs2_header,
+            client_first_bare: client_first_bare.to_string(),
+            server_first: server_first.clone(),
+            nonce: nonce,
+            credentials: credentials,
+        };
+        Ok((exchange, server_first.into_bytes()))
+    }
+
+    /// Checks the client-final-message, returning the server-final-message
+    pub fn finish(self, client_final: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let client_final = str::from_utf8(client_final).map_err(|_| "Malformed SCRAM message")?;
+        let proof_start = client_final.rfind(",p=").ok_or("Malformed SCRAM message")?;
+        let without_proof = &client_final[..proof_start];
+        let proof = base64::decode(&client_final[proof_start + 3..]).map_err(|_| "Malformed SCRAM proof")?;
+        let mut attributes = without_proof.split(',');
+        let channel_binding = attribute(attributes.next(), 'c')?;
+        if channel_binding != base64::encode(self.gs2_header.as_bytes()) {
+            return Err("SCRAM channel binding does not match");
+        }
+        if attribute(attributes.next(), 'r')? != self.nonce {
+            return Err("SCRAM nonce does not match");
+        }
+        let credentials = match self.credentials {
+            Some(ref credentials) => credentials,
+            None => return Err("Unknown user"),
+        };
+
+        let auth_message = format!("{},{},{}", self.client_first_bare, self.server_first, without_proof);
+        let client_signature = hmac(&credentials.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err("Wrong password");
+        }
+        let client_key: Vec<u8> = proof.iter().zip(client_signature.iter()).map(|(p, s)| p ^ s).collect();
+        if !constant_time_eq(&Sha256::digest(&client_key), &credentials.stored_key) {
+            return Err("Wrong password");
+        }
+        let server_signature = hmac(&credentials.server_key, auth_message.as_bytes());
+        Ok(format!("v={}", base64::encode(&server_signature)).into_bytes())
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use hmac::Hmac;
+    use pbkdf2::pbkdf2;
+    use scram::*;
+
+    #[test]
+    fn decodes_escaped_user_names() {
+        assert_eq!(decode_name("a=2Cb=3D"), Ok("a,b=".to_string()));
+        assert_eq!(decode_name("a=b"), Err("Malformed SCRAM user name"));
+    }
+
+    #[test]
+    fn authenticates_with_the_stored_keys() {
+        let credentials = ScramCredentials::new(b"pencil", 4096);
+        let lookup = |name: &str| if name == "user" { Some(credentials.clone()) } else { Option::None };
+        let client_first = b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+        let (exchange, server_first) = ScramExchange::start(client_first, lookup).unwrap();
+        assert_eq!(exchange.username, "user");
+        let server_first = String::from_utf8(server_first).unwrap();
+        assert!(server_first.starts_with("r=rOprNGfwEbeRWgbNEkqO"));
+        assert!(server_first.ends_with(",i=4096"));
+
+        // What the client computes from the password and the server-first-message
+        let nonce = &server_first[2..server_first.find(",s=").unwrap()];
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("n=user,r=rOprNGfwEbeRWgbNEkqO,{},{}", server_first, without_proof);
+        let mut salted_password = vec![0u8; 32];
+        pbkdf2::<Hmac<Sha256>>(b"pencil", &credentials.salt, 4096, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let signature = hmac(&Sha256::digest(&client_key), auth_message.as_bytes());
+        let proof: Vec<u8> = client_key.iter().zip(signature.iter()).map(|(k, s)| k ^ s).collect();
+        let client_final = format!("{},p={}", without_proof, base64::encode(&proof));
+        let server_final = exchange.finish(client_final.as_bytes()).unwrap();
+        let server_signature = hmac(&hmac(&salted_password, b"Server Key"), auth_message.as_bytes());
+        assert_eq!(server_final, format!("v={}", base64::encode(&server_signature)).into_bytes());
+
+        let (exchange, _) = ScramExchange::start(client_first, lookup).unwrap();
+        let wrong = format!("c=biws,r={},p={}", nonce, base64::encode(&proof));
+        assert_eq!(exchange.finish(wrong.as_bytes()), Err("SCRAM nonce does not match"));
+        let (exchange, _) = ScramExchange::start(b"n,,n=nobody,r=abc", lookup).unwrap();
+        assert_eq!(exchange.finish(b"c=biws,r=x,p=AA=="), Err("SCRAM nonce does not match"));
+        assert!(ScramExchange::start(b"p=tls-unique,,n=user,r=abc", lookup).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+extern crate futures;
+extern crate futures_cpupool;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, BufReader, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::Future;
+use futures::future::{self, Loop};
+use futures::stream::Stream;
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures_cpupool::CpuPool;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Handle, Interval, Timeout};
+use tokio_io::AsyncRead;
+
+use auth::PasswordCheck;
+use broker::Broker;
+use config::ListenerConfig;
+use frame::read_frame;
+use logging::{with_context, ConnectionContext};
+use logic::*;
+use mqtt::ReasonCode;
+use mqtt::reader::*;
+use proxy::{read_proxy_header, ProxyHeader};
+
+/// How long a load balancer has to send the PROXY header of a new connection
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Held by every connection task, dropped once the connection is closed
+type ConnectionGuard = UnboundedSender<()>;
+
+/// Returns a guard to clone into connection tasks and a future which
+/// resolves when the guard and all of its clones have been dropped.
+fn connection_tracker() -> (ConnectionGuard, Box<dyn Future<Item = (), Error = ()>>) {
+    let (tx, rx) = unbounded();
+    (tx, Box::new(rx.for_each(|_| Ok(()))))
+}
+
+/* State shared by every listener and connection */
+#[derive(Clone)]
+struct Shared {
+    broker: Rc<RefCell<Broker>>,
+    // Connections still waiting for their PROXY header, not yet open in the broker
+    handshakes: Rc<Cell<usize>>,
+    // Runs password checks, which would stall every connection on the reactor thread
+    pool: CpuPool,
+    // Never read: shutdown waits for every clone of it to be dropped with its connection
+    #[allow(dead_code)]
+    guard: ConnectionGuard,
+    max_connections: usize,
+    max_packet_size: u32,
+    log_payloads: bool,
+}
+
+/// A broker running on a reactor, which listeners can be added to
+pub struct Server {
+    shared: Shared,
+    all_closed: Box<dyn Future<Item = (), Error = ()>>,
+}
+
+impl Server {
+    /// Takes the broker onto the reactor of `handle`, which also expires
+    /// its sessions and messages from now on
+    pub fn new(broker: Broker, handle: &Handle) -> Server {
+        let (guard, all_closed) = connection_tracker();
+        let shared = Shared {
+            max_connections: broker.max_connections(),
+            max_packet_size: broker.max_packet_size(),
+            log_payloads: broker.log_payloads(),
+            broker: Rc::new(RefCell::new(broker)),
+            handshakes: Rc::new(Cell::new(0)),
+            pool: CpuPool::new_num_cpus(),
+            guard: guard,
+        };
+        expire_periodically(shared.broker.clone(), handle);
+        Server {
+            shared: shared,
+            all_closed: all_closed,
+        }
+    }
+
+    /// The broker, for publishing, reloading and the like from outside a connection
+    pub fn broker(&self) -> Rc<RefCell<Broker>> {
+        self.shared.broker.clone()
+    }
+
+    /// Binds the listener's address, returning the address bound, which has the port
+    /// picked for port 0, and a future accepting connections until dropped
+    pub fn listen(
+        &self,
+        listener: &ListenerConfig,
+        handle: &Handle,
+    ) -> io::Result<(SocketAddr, Box<dyn Future<Item = (), Error = Error>>)> {
+        let addr = listener
+            .address
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "expected an IP address with a port"))?;
+        let tcp = TcpListener::bind(&addr, handle)?;
+        let addr = tcp.local_addr()?;
+        info!("Listening on {}", addr);
+        Ok((addr, accept(tcp, listener.proxy_protocol, self.shared.clone(), handle)))
+    }
+
+    /// Closes every connection, dropping the wills unless they should go out.
+    /// Resolves once all connections are gone, which needs the listener futures dropped first.
+    pub fn shutdown(self, publish_wills: bool) -> Box<dyn Future<Item = (), Error = ()>> {
+        // Dropping the outbound queues lets every writer flush what is pending and finish
+        self.shared.broker.borrow_mut().shutdown(publish_wills);
+        drop(self.shared);
+        self.all_closed
+    }
+}
+
+/// Sessions, messages, delayed wills and keep alives are checked once per second
+fn expire_periodically(broker: Rc<RefCell<Broker>>, handle: &Handle) {
+    let ticks = Interval::new(Duration::from_secs(1), handle).unwrap();
+    let expiry = ticks.for_each(move |_| {
+        broker.borrow_mut().expire();
+        Ok(())
+    });
+    handle.spawn(expiry.map_err(|e| error!("Expiry timer error: {}", e)));
+}
+
+fn accept(
+    tcp: TcpListener,
+    proxy_protocol: bool,
+    shared: Shared,
+    handle: &Handle,
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    let handle = handle.clone();
+    let listener = tcp.incoming().for_each(move |(stream, addr)| {
+        let open = shared.broker.borrow().connection_count() + shared.handshakes.get();
+        if shared.max_connections > 0 && open >= shared.max_connections {
+            warn!("Rejected connection from {}: connection limit reached", addr);
+            return Ok(());
+        }
+        if !proxy_protocol {
+            handle_connection(stream, ConnectionContext::new(addr), shared.clone(), &handle);
+            return Ok(());
+        }
+        // Behind a load balancer the real client address comes from the PROXY header
+        let deadline = Timeout::new(PROXY_HEADER_TIMEOUT, &handle)?.and_then(|_| {
+            Err::<(TcpStream, ProxyHeader), _>(Error::new(ErrorKind::TimedOut, "no PROXY header in time"))
+        });
+        let shared = shared.clone();
+        shared.handshakes.set(shared.handshakes.get() + 1);
+        let inner_handle = handle.clone();
+        let header = read_proxy_header(stream).select(deadline).then(move |result| {
+            shared.handshakes.set(shared.handshakes.get() - 1);
+            match result.map(|(header, _)| header).map_err(|(e, _)| e) {
+                // Local clients use unspecified addresses, a connection claiming one could pose as them
+                Ok((_, ProxyHeader { source: Some(source), .. })) if source.ip().is_unspecified() => {
+                    warn!("Rejected connection from {}: PROXY header source {} is unspecified", addr, source)
+                }
+                Ok((stream, header)) => {
+                    let context = ConnectionContext::new(header.source.unwrap_or(addr));
+                    handle_connection(stream, context, shared, &inner_handle);
+                }
+                Err(e) => warn!("Rejected connection from {}: {}", addr, e),
+            }
+            Ok(())
+        });
+        handle.spawn(header);
+        Ok(())
+    });
+    Box::new(listener)
+}
+
+/// How the reading loop of a connection goes on after a packet
+enum Next {
+    Read,
+    Close,
+    /// Read again once the password check of CONNECT has run
+    Check(PasswordCheck),
+}
+
+fn handle_packet(bytes: Bytes, connection: &mut Connection, shared: &Shared) -> Next {
+    let mut broker = shared.broker.borrow_mut();
+    broker.touch(&connection.context.peer);
+    let packet = match read_packet_with_version(bytes, connection.version) {
+        Ok(packet) => packet,
+        Err(e) => {
+            warn!("Malformed packet: {}", e);
+            if let Some(reply) = disconnect(connection, ReasonCode::MalformedPacket, e) {
+                broker.send(&connection.context.peer, reply);
+            }
+            broker.close(&connection.context.peer);
+            return Next::Close;
+        }
+    };
+    if shared.log_payloads {
+        debug!("Received {:?}", packet);
+    } else {
+        debug!("Received {}", packet);
+    }
+    let response = answer(packet, connection, &mut broker);
+    respond(response, connection, &mut broker)
+}
+
+/// Sends the reply to the client, if any, and closes the connection if it has to
+fn respond(response: Result<Response, &'static str>, connection: &mut Connection, broker: &mut Broker) -> Next {
+    let (reply, next) = match response {
+        Ok(Response::None) => (None, Next::Read),
+        Ok(Response::Reply(reply)) => (Some(reply), Next::Read),
+        Ok(Response::Close(reply)) => (reply, Next::Close),
+        Ok(Response::Check(check)) => (None, Next::Check(check)),
+        Err(e) => {
+            warn!("Protocol error: {}", e);
+            (disconnect(connection, ReasonCode::ProtocolError, e), Next::Close)
+        }
+    };
+    if let Some(reply) = reply {
+        broker.send(&connection.context.peer, reply);
+    }
+    if let Next::Close = next {
+        broker.close(&connection.context.peer);
+    }
+    next
+}
+
+fn handle_connection(stream: TcpStream, context: ConnectionContext, shared: Shared, handle: &Handle) {
+    let addr = context.peer;
+    with_context(&context, || info!("Connection opened"));
+    let (reader, writer) = stream.split();
+    let (tx, rx) = unbounded();
+    shared.broker.borrow_mut().open(addr, tx);
+
+    let inner = shared.clone();
+    let state = (BufReader::new(reader), Connection::new(context.clone()));
+    let socket_reader = future::loop_fn(state, move |(reader, mut connection)| {
+        let shared = inner.clone();
+        read_frame(reader, shared.max_packet_size).and_then(move |(reader, bytes)| {
+            let context = connection.context.clone();
+            let check = match with_context(&context, || handle_packet(bytes, &mut connection, &shared)) {
+                Next::Read => return future::Either::A(future::ok(Loop::Continue((reader, connection)))),
+                Next::Close => return future::Either::A(future::ok(Loop::Break(()))),
+                Next::Check(check) => check,
+            };
+            let verification = shared.pool.spawn_fn(move || Ok::<bool, ()>(check()));
+            future::Either::B(verification.then(move |passed| {
+                let next = with_context(&context, || {
+                    let mut broker = shared.broker.borrow_mut();
+                    let response = checked(passed.unwrap_or(false), &mut connection, &mut broker);
+                    respond(response, &mut connection, &mut broker)
+                });
+                match next {
+                    Next::Close => Ok(Loop::Break(())),
+                    _ => Ok(Loop::Continue((reader, connection))),
+                }
+            }))
+        })
+    });
+    // Stop reading once closed and wait for the writer to drain the queue
+    let socket_reader = socket_reader.and_then(|_| future::empty::<(), Error>());
+    let socket_reader = socket_reader.map_err(move |e| if e.kind() != ErrorKind::UnexpectedEof {
+        with_context(&context, || warn!("Connection error: {}", e));
+    });
+
+    let socket_writer = rx.fold(writer, |writer, msg| {
+        let amt = tokio_io::io::write_all(writer, msg);
+        let amt = amt.map(|(writer, _)| writer);
+        amt.map_err(|_| ())
+    });
+
+    let connection = socket_reader.select(socket_writer.map(|_| ()));
+    let context = ConnectionContext::new(addr);
+    handle.spawn(connection.then(move |_| {
+        with_context(&context, || {
+            shared.broker.borrow_mut().disconnect(&addr);
+            info!("Connection closed")
+        });
+        drop(shared);
+        Ok(())
+    }));
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    use config::Config;
+    use futures::future::Either;
+    use futures::sync::oneshot;
+    use server::*;
+    use tokio_core::reactor::Core;
+
+    fn exchange(stream: &mut TcpStream, packet: &[u8], expected: &[u8]) {
+        stream.write_all(packet).unwrap();
+        let mut reply = vec![0u8; expected.len()];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, expected);
+    }
+
+    #[test]
+    fn serves_clients_until_shut_down() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let server = Server::new(Broker::new(&Config::default()).unwrap(), &handle);
+        let config = ListenerConfig {
+            address: "127.0.0.1:0".to_string(),
+            proxy_protocol: false,
+        };
+        let (addr, listener) = server.listen(&config, &handle).unwrap();
+
+        let (connected, subscribed) = oneshot::channel();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let connect = b"\x10\x0D\x00\x04MQTT\x04\x02\x00\x3C\x00\x01c";
+            exchange(&mut stream, connect, b"\x20\x02\x00\x00");
+            exchange(&mut stream, b"\x82\x06\x00\x01\x00\x01a\x00", b"\x90\x03\x00\x01\x00");
+            exchange(&mut stream, b"\x30\x05\x00\x01ahi", b"\x30\x05\x00\x01ahi");
+            connected.send(()).unwrap();
+            // The server closes the connection once shut down
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            rest
+        });
+        match core.run(listener.select2(subscribed)) {
+            Ok(Either::B((_, listener))) => drop(listener),
+            _ => panic!("Expected the client to get through"),
+        }
+        assert_eq!(server.broker().borrow().connection_count(), 1);
+
+        let all_closed = server.shutdown(false);
+        let deadline = Timeout::new(Duration::from_secs(5), &handle).unwrap();
+        match core.run(all_closed.select2(deadline)) {
+            Ok(Either::A(_)) => {}
+            _ => panic!("Expected every connection to be closed"),
+        }
+        assert_eq!(client.join().unwrap(), Vec::<u8>::new());
+    }
+}
@@ -0,0 +1,218 @@
+/* Topic names and topic filters */
+
+/// Longest topic the two-byte string length prefix can carry
+pub const MAX_LENGTH: usize = 65535;
+
+/// Filters starting with this are `$share/<group>/<filter>` shared subscriptions
+const SHARE_PREFIX: &str = "$share/";
+
+fn validate(topic: &str) -> Result<(), &'static str> {
+    // Levels may be empty ("a//b" is valid), the topic as a whole may not
+    if topic.is_empty() {
+        return Err("Topic must be at least one character long");
+    }
+    if topic.len() > MAX_LENGTH {
+        return Err("Topic is longer than 65535 bytes");
+    }
+    if topic.contains('\u{0}') {
+        return Err("Topic contains the null character U+0000");
+    }
+    Ok(())
+}
+
+/// Checks a topic name as used in PUBLISH and wills
+pub fn validate_name(name: &str) -> Result<(), &'static str> {
+    validate(name)?;
+    if has_wildcards(name) {
+        return Err("Wildcards are not allowed in topic names");
+    }
+    Ok(())
+}
+
+/// Checks a topic filter as used in SUBSCRIBE and UNSUBSCRIBE
+pub fn validate_filter(filter: &str) -> Result<(), &'static str> {
+    validate(filter)?;
+    if filter.starts_with(SHARE_PREFIX) || filter == "$share" {
+        return match split_shared(filter) {
+            Some((group, _)) if has_wildcards(group) => Err("Share name must not contain wildcards"),
+            Some((group, inner)) if !group.is_empty() && !inner.is_empty() => validate_filter(inner),
+            _ => Err("Shared subscription needs a share name and a topic filter"),
+        };
+    }
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+            return Err("Multi-level wildcard must be the last level on its own");
+        }
+        if level.contains('+') && level != "+" {
+            return Err("Single-level wildcard must occupy an entire level");
+        }
+    }
+    Ok(())
+}
+
+/// Checks the broker's configured limits, zero meaning no limit
+pub fn check_limits(topic: &str, max_length: usize, max_levels: usize) -> Result<(), &'static str> {
+    if max_length > 0 && topic.len() > max_length {
+        return Err("Topic exceeds the configured maximum length");
+    }
+    if max_levels > 0 && topic.split('/').count() > max_levels {
+        return Err("Topic exceeds the configured maximum number of levels");
+    }
+    Ok(())
+}
+
+/// Splits `$share/<group>/<filter>` into the share name and the filter
+pub fn split_shared(filter: &str) -> Option<(&str, &str)> {
+    if !filter.starts_with(SHARE_PREFIX) {
+        return None;
+    }
+    let rest = &filter[SHARE_PREFIX.len()..];
+    let slash = rest.find('/')?;
+    Some((&rest[..slash], &rest[slash + 1..]))
+}
+
+pub fn has_wildcards(filter: &str) -> bool {
+    filter.contains('+') || filter.contains('#')
+}
+
+/// Checks whether a topic name matches a topic filter. Filters starting with a
+/// wildcard do not match topics starting with `$` (e.g. `$SYS`).
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Whether every topic matched by `filter` is also matched by `allowed`
+pub fn covers(allowed: &str, filter: &str) -> bool {
+    if filter.starts_with('$') && (allowed.starts_with('+') || allowed.starts_with('#')) {
+        return false;
+    }
+    let mut allowed_levels = allowed.split('/');
+    let mut filter_levels = filter.split('/');
+    loop {
+        match (allowed_levels.next(), filter_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(f)) if f != "#" => {}
+            (Some(a), Some(f)) if a == f => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/* Tests */
+#[cfg(test)]
+mod tests {
+    use topic::*;
+
+    #[test]
+    fn matches_exact_topics() {
+        assert!(matches("a/b", "a/b"));
+        assert!(!matches("a/b", "a/c"));
+        assert!(!matches("a/b", "a/b/c"));
+        assert!(!matches("a/b/c", "a/b"));
+        assert!(matches("/", "/"));
+    }
+
+    #[test]
+    fn matches_single_level_wildcard() {
+        assert!(matches("sport/+/player1", "sport/tennis/player1"));
+        assert!(!matches("sport/+", "sport/tennis/player1"));
+        assert!(matches("sport/+", "sport/"));
+        assert!(matches("+/+", "/finance"));
+        assert!(matches("+", "finance"));
+        assert!(!matches("+", "/finance"));
+    }
+
+    #[test]
+    fn matches_multi_level_wildcard() {
+        assert!(matches("sport/#", "sport"));
+        assert!(matches("sport/#", "sport/tennis/player1"));
+        assert!(matches("#", "sport/tennis"));
+        assert!(!matches("sport/#", "sports"));
+    }
+
+    #[test]
+    fn skips_system_topics_for_leading_wildcards() {
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn validates_topic_names() {
+        assert!(validate_name("a//b").is_ok());
+        assert!(validate_name("/").is_ok());
+        assert_eq!(validate_name(""), Err("Topic must be at least one character long"));
+        assert_eq!(validate_name("a/+"), Err("Wildcards are not allowed in topic names"));
+        assert_eq!(validate_name("a/#"), Err("Wildcards are not allowed in topic names"));
+        assert_eq!(validate_name("a\u{0}b"), Err("Topic contains the null character U+0000"));
+        assert!(validate_name(&"a".repeat(65536)).is_err());
+    }
+
+    #[test]
+    fn validates_topic_filters() {
+        for filter in &["#", "+", "a/#", "+/+/#", "/+", "a//+"] {
+            assert!(validate_filter(filter).is_ok(), "{}", filter);
+        }
+        for filter in &["a/#/b", "a#", "#/", "a/b#"] {
+            assert_eq!(
+                validate_filter(filter),
+                Err("Multi-level wildcard must be the last level on its own")
+            );
+        }
+        for filter in &["a+", "a/+b", "++"] {
+            assert_eq!(
+                validate_filter(filter),
+                Err("Single-level wildcard must occupy an entire level")
+            );
+        }
+    }
+
+    #[test]
+    fn validates_shared_subscriptions() {
+        assert_eq!(split_shared("$share/workers/a/+"), Some(("workers", "a/+")));
+        assert_eq!(split_shared("a/b"), None);
+        assert!(validate_filter("$share/workers/#").is_ok());
+        for filter in &["$share", "$share/", "$share/workers", "$share//a", "$share/workers/"] {
+            assert_eq!(
+                validate_filter(filter),
+                Err("Shared subscription needs a share name and a topic filter"),
+                "{}",
+                filter
+            );
+        }
+        assert_eq!(validate_filter("$share/+/a"), Err("Share name must not contain wildcards"));
+        assert!(validate_filter("$share/g/a#").is_err());
+    }
+
+    #[test]
+    fn checks_configured_limits() {
+        assert!(check_limits("a/b/c", 0, 0).is_ok());
+        assert!(check_limits("a/b/c", 5, 3).is_ok());
+        assert!(check_limits("a/b/c", 4, 0).is_err());
+        assert!(check_limits("a/b/c", 0, 2).is_err());
+    }
+
+    #[test]
+    fn checks_filter_coverage() {
+        assert!(covers("a/#", "a/+/c"));
+        assert!(covers("a/+", "a/b"));
+        assert!(!covers("a/+", "a/#"));
+        assert!(!covers("a/b", "a/+"));
+        assert!(!covers("#", "$SYS/#"));
+    }
+}